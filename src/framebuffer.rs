@@ -0,0 +1,339 @@
+use image::{ImageBuffer, Rgb, RgbImage, Rgba, RgbaImage};
+use nalgebra::Vector4;
+use super::state::DepthState;
+
+// Number of samples stored per pixel
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SampleCount {
+    X1,
+    X2,
+    X4,
+    X8
+}
+
+// Standard D3D sample patterns in 1/16 pixel units from the pixel centre,
+// y is flipped since screen y points up
+const PATTERN_1X : [(i64, i64); 1] = [(0, 0)];
+const PATTERN_2X : [(i64, i64); 2] = [(4, -4), (-4, 4)];
+const PATTERN_4X : [(i64, i64); 4] = [(-2, 6), (6, 2), (-6, -2), (2, -6)];
+const PATTERN_8X : [(i64, i64); 8] = [(1, 3), (-1, -3), (5, -1), (-3, 5), (-5, -5), (-7, 1), (3, -7), (7, 7)];
+
+impl SampleCount {
+    pub fn count(self) -> usize {
+        self.pattern().len()
+    }
+
+    pub fn pattern(self) -> &'static [(i64, i64)] {
+        match self {
+            SampleCount::X1 => &PATTERN_1X,
+            SampleCount::X2 => &PATTERN_2X,
+            SampleCount::X4 => &PATTERN_4X,
+            SampleCount::X8 => &PATTERN_8X
+        }
+    }
+}
+
+// Pixel format of a colour attachment
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    Rgb8,
+    Rgba8,
+    R32F,
+    Rgba32F
+}
+
+impl Format {
+    pub fn channels(self) -> usize {
+        match self {
+            Format::Rgb8 => 3,
+            Format::Rgba8 => 4,
+            Format::R32F => 1,
+            Format::Rgba32F => 4
+        }
+    }
+}
+
+// Most colour attachments a framebuffer can have, also the number of colours a
+// fragment shader can output
+pub const MAX_COLOR_ATTACHMENTS : usize = 4;
+
+enum Storage {
+    U8(Vec<u8>),
+    F32(Vec<f32>)
+}
+
+// Mutable view of the samples of a colour attachment
+pub(crate) enum ColorSlice<'a> {
+    U8(&'a mut [u8]),
+    F32(&'a mut [f32])
+}
+
+pub struct ColorAttachment {
+    format : Format,
+    data : Storage
+}
+
+// Colours are passed around as RGBA in 0..1 for 8-bit formats, channels missing
+// from a format read back as 0 and alpha as 1
+fn load_u8(format : Format, data : &[u8], sample : usize) -> Vector4<f32> {
+    let c = format.channels();
+    let v = &data[sample * c..(sample + 1) * c];
+    let a = if c == 4 {v[3]} else {255};
+    Vector4::new(v[0] as f32, v[1] as f32, v[2] as f32, a as f32) / 255.
+}
+
+fn load_f32(format : Format, data : &[f32], sample : usize) -> Vector4<f32> {
+    let c = format.channels();
+    let v = &data[sample * c..(sample + 1) * c];
+    if c == 4 {Vector4::new(v[0], v[1], v[2], v[3])} else {Vector4::new(v[0], 0., 0., 1.)}
+}
+
+fn to_u8(v : f32) -> u8 {
+    (v.clamp(0., 1.) * 255.).round() as u8
+}
+
+impl ColorAttachment {
+    fn new(format : Format, samples : usize) -> ColorAttachment {
+        let len = samples * format.channels();
+        let data = match format {
+            Format::Rgb8 | Format::Rgba8 => Storage::U8(vec![0; len]),
+            Format::R32F | Format::Rgba32F => Storage::F32(vec![0.; len])
+        };
+        ColorAttachment { format, data }
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn load(&self, sample : usize) -> Vector4<f32> {
+        match &self.data {
+            Storage::U8(d) => load_u8(self.format, d, sample),
+            Storage::F32(d) => load_f32(self.format, d, sample)
+        }
+    }
+
+    fn clear(&mut self, value : Vector4<f32>) {
+        let c = self.format.channels();
+        match &mut self.data {
+            Storage::U8(d) => {
+                for (i, v) in d.iter_mut().enumerate() {
+                    *v = to_u8(value[i % c]);
+                }
+            },
+            Storage::F32(d) => {
+                for (i, v) in d.iter_mut().enumerate() {
+                    *v = value[i % c];
+                }
+            }
+        }
+    }
+
+    fn chunks_mut(&mut self, samples : usize) -> Vec<ColorSlice<'_>> {
+        let len = samples * self.format.channels();
+        match &mut self.data {
+            Storage::U8(d) => d.chunks_mut(len).map(ColorSlice::U8).collect(),
+            Storage::F32(d) => d.chunks_mut(len).map(ColorSlice::F32).collect()
+        }
+    }
+}
+
+pub(crate) struct ColorView<'a> {
+    format : Format,
+    data : ColorSlice<'a>
+}
+
+impl ColorView<'_> {
+    pub(crate) fn load(&self, sample : usize) -> Vector4<f32> {
+        match &self.data {
+            ColorSlice::U8(d) => load_u8(self.format, d, sample),
+            ColorSlice::F32(d) => load_f32(self.format, d, sample)
+        }
+    }
+
+    pub(crate) fn store(&mut self, sample : usize, value : &Vector4<f32>) {
+        let c = self.format.channels();
+        match &mut self.data {
+            ColorSlice::U8(d) => {
+                for (i, v) in d[sample * c..(sample + 1) * c].iter_mut().enumerate() {
+                    *v = to_u8(value[i]);
+                }
+            },
+            ColorSlice::F32(d) => d[sample * c..(sample + 1) * c].copy_from_slice(&value.as_slice()[..c])
+        }
+    }
+}
+
+// Hierarchical depth is kept for square blocks of this many pixels
+pub(crate) const HIZ_BLOCK : u32 = 8;
+
+// Bounds of the depth stored in a block. Writing depth only marks the block dirty, the
+// bounds are recomputed the next time they are needed
+#[derive(Clone, Copy)]
+pub(crate) struct HizBlock {
+    pub(crate) min : f32,
+    pub(crate) max : f32,
+    pub(crate) dirty : bool
+}
+
+impl HizBlock {
+    fn new(value : f32) -> HizBlock {
+        HizBlock { min : value, max : value, dirty : false }
+    }
+}
+
+// The part of a framebuffer covered by one row of tiles, screen rows y_min..y_max
+pub(crate) struct TileRow<'a> {
+    pub(crate) y_min : u32,
+    pub(crate) y_max : u32,
+    pub(crate) colors : Vec<ColorView<'a>>,
+    pub(crate) depth : &'a mut [f32],
+    pub(crate) stencil : Option<&'a mut [u8]>,
+    pub(crate) hiz : &'a mut [HizBlock]
+}
+
+// Colour attachments, depth and optional stencil storage. Every pixel holds one value
+// per sample with the samples of a pixel stored next to each other. Rows run bottom
+// to top like screen y. Depth is private so that only the pipeline and clear_depth
+// write it, which keep the hierarchical depth blocks in sync with it
+pub struct Framebuffer {
+    pub width : u32,
+    pub height : u32,
+    pub samples : SampleCount,
+    pub colors : Vec<ColorAttachment>,
+    depth : Vec<f32>,
+    pub stencil : Option<Vec<u8>>,
+    pub(crate) hiz : Vec<HizBlock>
+}
+
+impl Framebuffer {
+    // A framebuffer with only a depth buffer, add colour attachments with with_color.
+    // Depth starts out cleared for the default DepthState
+    pub fn new(width : u32, height : u32, samples : SampleCount) -> Framebuffer {
+        let len = (width * height) as usize * samples.count();
+        let clear = DepthState::default().clear_value;
+        Framebuffer {
+            width,
+            height,
+            samples,
+            colors : Vec::new(),
+            depth : vec![clear; len],
+            stencil : None,
+            hiz : vec![HizBlock::new(clear); (width.div_ceil(HIZ_BLOCK) * height.div_ceil(HIZ_BLOCK)) as usize]
+        }
+    }
+
+    // Add a colour attachment cleared to zero, fragment output i goes to attachment i
+    pub fn with_color(mut self, format : Format) -> Framebuffer {
+        assert!(self.colors.len() < MAX_COLOR_ATTACHMENTS, "too many colour attachments");
+        self.colors.push(ColorAttachment::new(format, self.depth.len()));
+        self
+    }
+
+    // Add an 8-bit stencil buffer cleared to zero
+    pub fn with_stencil(mut self) -> Framebuffer {
+        self.stencil = Some(vec![0; self.depth.len()]);
+        self
+    }
+
+    pub fn clear_color(&mut self, index : usize, value : Vector4<f32>) {
+        self.colors[index].clear(value);
+    }
+
+    // Depth of every sample, laid out like the colour attachments
+    pub fn depth(&self) -> &[f32] {
+        &self.depth
+    }
+
+    pub fn clear_depth(&mut self, value : f32) {
+        for d in self.depth.iter_mut() {
+            *d = value;
+        }
+        for b in self.hiz.iter_mut() {
+            *b = HizBlock::new(value);
+        }
+    }
+
+    // Clear depth to the clear value of the depth state the next draws use
+    pub fn clear_depth_for(&mut self, depth : &DepthState) {
+        self.clear_depth(depth.clear_value);
+    }
+
+    pub fn clear_stencil(&mut self, value : u8) {
+        if let Some(stencil) = self.stencil.as_mut() {
+            for s in stencil.iter_mut() {
+                *s = value;
+            }
+        }
+    }
+
+    // Split every buffer into rows of tiles that can be written to independently, the
+    // tile size has to be a multiple of HIZ_BLOCK
+    pub(crate) fn tile_rows(&mut self, tile_size : u32) -> Vec<TileRow<'_>> {
+        assert!(tile_size % HIZ_BLOCK == 0);
+        let (width, height) = (self.width, self.height);
+        let row_len = (width * tile_size) as usize * self.samples.count();
+        let mut hiz = self.hiz.chunks_mut((width.div_ceil(HIZ_BLOCK) * tile_size / HIZ_BLOCK) as usize);
+        let mut colors : Vec<_> = self.colors.iter_mut().map(|c| {
+            let format = c.format;
+            c.chunks_mut(row_len).into_iter().map(move |data| ColorView { format, data })
+        }).collect();
+        let mut stencil = self.stencil.as_mut().map(|s| s.chunks_mut(row_len));
+        self.depth.chunks_mut(row_len).enumerate().map(|(k, depth)| {
+            let y_min = k as u32 * tile_size;
+            TileRow {
+                y_min,
+                y_max : (y_min + tile_size).min(height),
+                colors : colors.iter_mut().map(|c| c.next().unwrap()).collect(),
+                depth,
+                stencil : stencil.as_mut().map(|s| s.next().unwrap()),
+                hiz : hiz.next().unwrap()
+            }
+        }).collect()
+    }
+
+    // Average the samples of a pixel
+    fn resolve_pixel(&self, index : usize, x : u32, y : u32) -> Vector4<f32> {
+        let n = self.samples.count();
+        let idx = (x + y * self.width) as usize * n;
+        let sum = (idx..idx + n).fold(Vector4::zeros(), |sum, s| sum + self.colors[index].load(s));
+        sum / n as f32
+    }
+
+    // Resolve a colour attachment into an image, flipping rows to image order
+    pub fn resolve(&self, index : usize) -> RgbImage {
+        let mut img : RgbImage = ImageBuffer::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let c = self.resolve_pixel(index, x, y);
+                img.put_pixel(x, self.height - y - 1, Rgb([to_u8(c.x), to_u8(c.y), to_u8(c.z)]));
+            }
+        }
+        img
+    }
+
+    pub fn resolve_rgba(&self, index : usize) -> RgbaImage {
+        let mut img : RgbaImage = ImageBuffer::new(self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let c = self.resolve_pixel(index, x, y);
+                img.put_pixel(x, self.height - y - 1, Rgba([to_u8(c.x), to_u8(c.y), to_u8(c.z), to_u8(c.w)]));
+            }
+        }
+        img
+    }
+
+    // Resolve a colour attachment to floats, with as many channels per pixel as the
+    // format has and rows in image order
+    pub fn resolve_f32(&self, index : usize) -> Vec<f32> {
+        let c = self.colors[index].format.channels();
+        let mut out = Vec::with_capacity((self.width * self.height) as usize * c);
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let v = self.resolve_pixel(index, x, y);
+                out.extend_from_slice(&v.as_slice()[..c]);
+            }
+        }
+        out
+    }
+}
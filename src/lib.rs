@@ -0,0 +1,10 @@
+pub mod framebuffer;
+pub mod render;
+pub mod transforms;
+pub mod shader;
+pub mod shadow;
+pub mod state;
+pub mod stats;
+pub mod tangents;
+pub mod varyings;
+pub mod wireframe;
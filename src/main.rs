@@ -1,20 +1,18 @@
-mod render;
-mod transforms;
-mod shader;
-
-use image::{ImageBuffer, RgbImage};
+use raster::{render, shader, shadow, state, tangents, transforms};
+use raster::framebuffer::{Format, Framebuffer, SampleCount};
 use nalgebra::{Vector3, Matrix4, Matrix3};
 use std::env;
 
 fn main() {
     let args : Vec<String> = env::args().collect();
-    if env::args().len() != 4 {
-        println!("Expecting 3 arguments, exiting...");
+    if args.len() != 4 && args.len() != 5 {
+        println!("Expecting 3 or 4 arguments, exiting...");
         return
     }
     let obj_path = &args[1];
     let diffuse_path = &args[2];
     let spec_path = &args[3];
+    let normal_map = args.get(4).map(|path| image::open(path).expect("can't open normal map").to_rgb());
 
     let width = 1000;
     let height = 1000;
@@ -24,7 +22,6 @@ fn main() {
     let g = Vector3::new(0., 0., -1.);
     let t = Vector3::new(0., 1., 0.);
 
-    let mut img : RgbImage = ImageBuffer::new(width, height);
     let obj = tobj::load_obj(obj_path, true);
     assert!(obj.is_ok());
     let diffuse = image::open(diffuse_path);
@@ -35,9 +32,8 @@ fn main() {
     let diffuse = diffuse.unwrap().to_rgb();
     let spec = spec.unwrap().to_rgb();
 
-    let mut z_buf = vec![f32::MIN;(width * height) as usize];
+    let mut fb = Framebuffer::new(width, height, SampleCount::X4).with_color(Format::Rgb8);
 
-    let m_vp = transforms::viewport(width, height);
     let m_per = transforms::perspective(-1., 1., -1., 1., -3., -5.);
     let model_affine = Matrix4::new(1., 0., 0., 0.,
                 0., 1., 0., 0.,
@@ -47,9 +43,8 @@ fn main() {
                 0., 1., 0.,
                 0., 0., 1.);
     let m_cam = transforms::camera(e, g, t);
-    let m = m_vp * m_per * m_cam * model_affine;
+    let m = m_per * m_cam * model_affine;
 
-    let len = obj[0].mesh.indices.len() / 3;
     let mesh = &obj[0].mesh;
     let id = &mesh.indices;
     let pos = &mesh.positions;
@@ -57,9 +52,12 @@ fn main() {
     let normals = &mesh.normals;
     let light_source = vec!((Vector3::new(0., 0., -1.), 0.8));
 
+    // The model fits in a unit cube around its centre, so a sphere of radius 2 covers it
+    let mut shadow_map = shadow::ShadowMap::directional(light_source[0].0, Vector3::new(0., 0., -4.), 2., 1024);
+    shadow_map.draw(id, pos, &model_affine);
+
     // let s : Box<dyn shader::Shader> = Box::new(shader::VanillaShader {
     //     m : m,
-    //     indices : id,
     //     positions : pos,
     //     texcoords : texcoords,
     //     diffuse_width : diffuse.width(),
@@ -69,33 +67,66 @@ fn main() {
     
     // let s_l = shader::GouraudShader {
     //     mvp : m,
+    //     world : model_affine,
     //     model : model,
-    //     indices : id,
     //     positions : pos,
     //     texcoords : texcoords,
     //     diffuse : &diffuse,
     //     normals : normals,
     //     ambient : 0.2,
-    //     light_source : &light_source
+    //     light_source : &light_source,
+    //     shadow : Some(&shadow_map)
     // };
 
     let s_l = shader::BlinnPhongShader {
         mvp : m,
-        model : model,
-        e : -g,
-        indices : id,
+        world : model_affine,
+        model,
+        eye : e,
         positions : pos,
-        texcoords : texcoords,
+        texcoords,
         diffuse : &diffuse,
         spec : &spec,
-        normals : normals,
+        normals,
         ambient : 0.2,
         light_source : &light_source,
+        shadow : Some(&shadow_map),
         phong_exp : 2.
     };
 
-    let b : Box<dyn shader::Shader> = Box::new(s_l);
+    // Wavefront obj faces wind counter-clockwise seen from outside the model
+    let state = state::PipelineState {
+        cull_mode : state::CullMode::Back,
+        front_face : state::FrontFace::CounterClockwise,
+        ..Default::default()
+    };
+    fb.clear_depth_for(&state.depth);
 
-    render::rasterize(len, &b, &mut z_buf, &mut img);
-    img.save("out.png").unwrap();
+    match &normal_map {
+        Some(normal_map) => {
+            let tangents = tangents::generate(id, pos, normals, texcoords);
+            let s_n = shader::NormalMapShader {
+                mvp : m,
+                world : model_affine,
+                model,
+                eye : e,
+                positions : pos,
+                texcoords,
+                normals,
+                tangents : &tangents,
+                diffuse : &diffuse,
+                spec : &spec,
+                normal_map,
+                ambient : 0.2,
+                light_source : &light_source,
+                shadow : Some(&shadow_map),
+                phong_exp : 2.
+            };
+            render::rasterize_indexed(id, &s_n, &state, &mut fb);
+        },
+        None => {
+            render::rasterize_indexed(id, &s_l, &state, &mut fb);
+        }
+    }
+    fb.resolve(0).save("out.png").unwrap();
 }
\ No newline at end of file
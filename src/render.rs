@@ -1,83 +1,1570 @@
-use image::{RgbImage};
-use nalgebra::{Vector2, Vector4};
-use super::shader::{Shader, VertexAttr};
-
-fn baycentric2d(x : f32, y : f32, v : (Vector4<f32>, Vector4<f32>, Vector4<f32>)) -> (f32, f32, f32) {
-    let c1 = ((v.1.y - v.2.y)*(x - v.2.x) + (v.2.x - v.1.x)*(y - v.2.y)) 
-        / ((v.1.y - v.2.y) * (v.0.x - v.2.x) + (v.2.x - v.1.x) * (v.0.y - v.2.y));
-    let c2 = ((v.2.y - v.0.y)*(x - v.2.x) + (v.0.x - v.2.x)*(y - v.2.y)) 
-        / ((v.1.y - v.2.y) * (v.0.x - v.2.x) + (v.2.x - v.1.x) * (v.0.y - v.2.y));
-    let c3 = 1. - c1 - c2; 
-    (c1, c2, c3)
-}
-
-fn rasterize_triangle<'a>(vs : [(Vector4<f32>, Vec<VertexAttr>); 3], shader : &Box<dyn Shader + '_>, z_buffer : &mut Vec<f32>, img : &mut RgbImage) {
-
-    let img_bound = Vector2::new(img.width() as f32, img.height() as f32);
-    let mut bbmin = Vector2::new(img_bound[0] - 1., img_bound[1] - 1.);
-    let mut bbmax = Vector2::new(0., 0.);
-
-    // Generate bounding box
-    for i in 0..3 {
-         for j in 0..2 {
-            if vs[i].0[j] > bbmax[j] {
-                if vs[i].0[j] < img_bound[j] {
-                    bbmax[j] = vs[i].0[j];
-                 } else {
-                     bbmax[j] = img_bound[j];
-                 }
-            }
-            if vs[i].0[j] < bbmin[j] {
-                if vs[i].0[j] > 0. {
-                    bbmin[j] = vs[i].0[j];
+use nalgebra::Vector4;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+use super::framebuffer::{Framebuffer, HizBlock, TileRow, HIZ_BLOCK, MAX_COLOR_ATTACHMENTS};
+use super::shader::{FragmentInput, FragmentOutput, Quad, Shader};
+use super::stats::{DrawStats, Reject};
+use super::varyings::{Barycentrics, Varyings};
+use super::state::{CompareFunc, CullMode, DepthBias, DepthState, FrontFace, PipelineState, PrimitiveTopology, StencilFace, Viewport};
+
+// Area is twice the signed screen-space area, positive when counter-clockwise
+fn is_front_facing(area : i64, state : &PipelineState) -> bool {
+    match state.front_face {
+        FrontFace::CounterClockwise => area > 0,
+        FrontFace::Clockwise => area < 0
+    }
+}
+
+fn is_culled(front : bool, state : &PipelineState) -> bool {
+    match state.cull_mode {
+        CullMode::None => false,
+        CullMode::Back => !front,
+        CullMode::Front => front
+    }
+}
+
+// A vertex as it leaves the vertex stage, position in clip space
+type ClipVertex<V> = (Vector4<f32>, V);
+
+// Clip space planes as coefficients of (x, y, z, w), the dot product with a position is
+// its signed distance to the plane, positive inside. Near is z <= w and far is z >= -w,
+// with reversed-Z depth runs from 0 at the far plane to 1 at the near plane. The guard
+// band planes |x| <= k.0 * w and |y| <= k.1 * w keep clipped primitives small enough
+// for the fixed point rasterizer, well outside the viewport
+fn clip_planes(depth : &DepthState, k : (f32, f32)) -> [Vector4<f32>; 6] {
+    [
+        Vector4::new(0., 0., -1., 1.),
+        if depth.reversed_z {Vector4::new(0., 0., 1., 0.)} else {Vector4::new(0., 0., 1., 1.)},
+        Vector4::new(-1., 0., 0., k.0),
+        Vector4::new(1., 0., 0., k.0),
+        Vector4::new(0., -1., 0., k.1),
+        Vector4::new(0., 1., 0., k.1)
+    ]
+}
+
+// The point t of the way from a to b in clip space. Perspective correct varyings blend
+// linearly in clip space, screen-linear ones by where the point lands on screen between
+// a and b, and flat ones keep the value of a
+fn lerp_vertex<V : Varyings>(a : &ClipVertex<V>, b : &ClipVertex<V>, t : f32) -> ClipVertex<V> {
+    let (wa, wb) = (a.0.w, b.0.w);
+    // Behind the eye there is no screen position to go by
+    let s = if wa > 0. && wb > 0. {t * wb / ((1. - t) * wa + t * wb)} else {t};
+    let bc = Barycentrics { perspective : (1. - t, t, 0.), linear : (1. - s, s, 0.), provoking : 0 };
+    (a.0 + (b.0 - a.0) * t, V::interpolate([&a.1, &b.1, &b.1], &bc))
+}
+
+// Sutherland-Hodgman against a single plane
+fn clip_polygon<V : Varyings>(poly : Vec<ClipVertex<V>>, plane : &Vector4<f32>) -> Vec<ClipVertex<V>> {
+    let mut out = Vec::with_capacity(poly.len() + 1);
+    for i in 0..poly.len() {
+        let cur = &poly[i];
+        let next = &poly[(i + 1) % poly.len()];
+        let (d0, d1) = (plane.dot(&cur.0), plane.dot(&next.0));
+        if d0 >= 0. {
+            out.push(cur.clone());
+        }
+        if (d0 >= 0.) != (d1 >= 0.) {
+            out.push(lerp_vertex(cur, next, d0 / (d0 - d1)));
+        }
+    }
+    out
+}
+
+// Clip a triangle against the clip planes, returns the resulting convex polygon
+fn clip_triangle<V : Varyings>(t : [ClipVertex<V>; 3], planes : &[Vector4<f32>]) -> Vec<ClipVertex<V>> {
+    let mut poly = t.to_vec();
+    let mut clipped = false;
+    for plane in planes.iter() {
+        if poly.iter().all(|v| plane.dot(&v.0) >= 0.) {
+            continue;
+        }
+        poly = clip_polygon(poly, plane);
+        clipped = true;
+        if poly.len() < 3 {
+            return Vec::new();
+        }
+    }
+    // Every piece of the triangle takes its flat varyings from the provoking vertex,
+    // which may have been clipped away
+    if clipped {
+        let bc = Barycentrics { perspective : (1., 0., 0.), linear : (1., 0., 0.), provoking : 1 };
+        for v in poly.iter_mut() {
+            v.1 = V::interpolate([&v.1, &t[0].1, &v.1], &bc);
+        }
+    }
+    poly
+}
+
+// Clip a line against the clip planes, moving whichever end lies outside
+fn clip_line<V : Varyings>(l : [ClipVertex<V>; 2], planes : &[Vector4<f32>]) -> Option<[ClipVertex<V>; 2]> {
+    let [mut a, mut b] = l;
+    for plane in planes.iter() {
+        let (d0, d1) = (plane.dot(&a.0), plane.dot(&b.0));
+        if d0 < 0. && d1 < 0. {
+            return None;
+        }
+        if d0 < 0. {
+            a = lerp_vertex(&a, &b, d0 / (d0 - d1));
+        } else if d1 < 0. {
+            b = lerp_vertex(&a, &b, d0 / (d0 - d1));
+        }
+    }
+    Some([a, b])
+}
+
+// Perspective divide and viewport transform, keep 1/w in the w component for perspective
+// correct interpolation. Normalized depth runs from -1 at the far plane to 1 at the near
+// plane, or from 0 with reversed-Z
+fn to_window<V>(v : ClipVertex<V>, vp : &Viewport, depth : &DepthState) -> ClipVertex<V> {
+    let (p, vary) = v;
+    let w_reci = 1. / p.w;
+    let (x, y, z) = (p.x * w_reci, p.y * w_reci, p.z * w_reci);
+    let z = if depth.reversed_z {z} else {(z + 1.) / 2.};
+    let x = vp.x + (x + 1.) / 2. * vp.width;
+    let y = vp.y + (y + 1.) / 2. * vp.height;
+    let z = vp.min_depth + z * (vp.max_depth - vp.min_depth);
+    (Vector4::new(x, y, z, w_reci), vary)
+}
+
+// Vertex positions are snapped to a fixed point grid with this many fractional bits
+// before edge setup, so edge functions are evaluated exactly in integers
+const SUBPIXEL_BITS : i64 = 8;
+const SUBPIXEL_ONE : i64 = 1 << SUBPIXEL_BITS;
+
+// Edge function E(x, y) = a * x + b * y + c in sub-pixel units, positive on the inside
+// of a counter-clockwise triangle
+#[derive(Clone, Copy, Debug)]
+struct Edge {
+    a : i64,
+    b : i64,
+    c : i64,
+    // 0 for top-left edges, -1 otherwise, so pixels exactly on an edge are owned by
+    // exactly one of the two triangles sharing it
+    bias : i64
+}
+
+impl Edge {
+    fn new(p0 : (i64, i64), p1 : (i64, i64)) -> Edge {
+        let (dx, dy) = (p1.0 - p0.0, p1.1 - p0.1);
+        // Screen y points up, so for counter-clockwise triangles left edges run
+        // downwards and top edges run to the left
+        let top_left = dy < 0 || (dy == 0 && dx < 0);
+        Edge {
+            a : -dy,
+            b : dx,
+            c : dy * p0.0 - dx * p0.1,
+            bias : if top_left {0} else {-1}
+        }
+    }
+
+    fn eval(&self, x : i64, y : i64) -> i64 {
+        self.a * x + self.b * y + self.c
+    }
+}
+
+fn snap(v : f32) -> i64 {
+    (v * SUBPIXEL_ONE as f32).round() as i64
+}
+
+// Triangles are binned into square screen tiles, each row of tiles is rasterized by
+// a single thread so every pixel still sees its triangles in submission order and
+// the output is the same no matter how many threads are used
+const TILE_SIZE : u32 = 32;
+
+// Pixel rectangle, min inclusive and max exclusive
+type Rect = ((u32, u32), (u32, u32));
+
+// A triangle ready for rasterization, after clipping, perspective divide and culling.
+// Vertices are reordered to wind counter-clockwise, leaving the provoking vertex in
+// vs[0], and edges[i] is opposite to vs[i]
+struct Triangle<V> {
+    vs : [ClipVertex<V>; 3],
+    edges : [Edge; 3],
+    area : i64,
+    front : bool,
+    conservative : bool,
+    // Depth bias added to every fragment
+    bias : f32,
+    // Pixel bounding box, min inclusive and max exclusive
+    bbmin : (u32, u32),
+    bbmax : (u32, u32)
+}
+
+// A line segment after clipping and perspective divide
+struct Line<V> {
+    vs : [ClipVertex<V>; 2],
+    bias : f32,
+    bbmin : (u32, u32),
+    bbmax : (u32, u32)
+}
+
+// A point after perspective divide and the pixel it covers
+struct Point<V> {
+    v : ClipVertex<V>,
+    bias : f32,
+    x : u32,
+    y : u32
+}
+
+enum Primitive<V> {
+    Triangle(Triangle<V>),
+    Line(Line<V>),
+    Point(Point<V>)
+}
+
+impl<V> Primitive<V> {
+    // Pixel bounding box
+    fn bounds(&self) -> Rect {
+        match self {
+            Primitive::Triangle(t) => (t.bbmin, t.bbmax),
+            Primitive::Line(l) => (l.bbmin, l.bbmax),
+            Primitive::Point(p) => ((p.x, p.y), (p.x + 1, p.y + 1))
+        }
+    }
+
+    // Lowest and highest depth of any fragment the primitive can generate
+    fn depth_bounds(&self) -> (f32, f32) {
+        let bounds = |z : &mut dyn Iterator<Item = f32>| z.fold((f32::MAX, f32::MIN), |(lo, hi), z| (lo.min(z), hi.max(z)));
+        let ((lo, hi), bias) = match self {
+            Primitive::Triangle(t) => (bounds(&mut t.vs.iter().map(|v| v.0.z)), t.bias),
+            Primitive::Line(l) => (bounds(&mut l.vs.iter().map(|v| v.0.z)), l.bias),
+            Primitive::Point(p) => ((p.v.0.z, p.v.0.z), p.bias)
+        };
+        (lo + bias, hi + bias)
+    }
+}
+
+impl TileRow<'_> {
+    // Stencil and depth test for the samples in mask starting at idx. Returns the
+    // samples that pass the stencil test and the samples that pass both tests
+    fn test(&self, idx : usize, mask : u32, z : &[f32], face : &StencilFace, state : &PipelineState) -> (u32, u32) {
+        let (st, depth) = (&state.stencil, &state.depth);
+        let mut stencil_pass = mask;
+        if let (true, Some(stencil)) = (st.enabled, self.stencil.as_ref()) {
+            for (s, stored) in stencil[idx..idx + z.len()].iter().enumerate() {
+                if !face.compare.test(st.reference & st.read_mask, stored & st.read_mask) {
+                    stencil_pass &= !(1 << s);
+                }
+            }
+        }
+        let mut depth_pass = stencil_pass;
+        for (s, (z, stored)) in z.iter().zip(self.depth[idx..idx + z.len()].iter()).enumerate() {
+            if !depth.compare.test(*z, *stored) {
+                depth_pass &= !(1 << s);
+            }
+        }
+        (stencil_pass, depth_pass)
+    }
+
+    // Apply the stencil operations for every sample in mask and write depth for the
+    // samples that passed
+    fn update(&mut self, idx : usize, mask : u32, pass : (u32, u32), z : &[f32], face : &StencilFace, state : &PipelineState) {
+        let (st, depth) = (&state.stencil, &state.depth);
+        let (stencil_pass, depth_pass) = pass;
+        if let (true, Some(stencil)) = (st.enabled, self.stencil.as_mut()) {
+            for (s, stored) in stencil[idx..idx + z.len()].iter_mut().enumerate() {
+                let bit = 1 << s;
+                if mask & bit == 0 {
+                    continue;
+                }
+                let op = if stencil_pass & bit == 0 {
+                    face.fail
+                } else if depth_pass & bit == 0 {
+                    face.depth_fail
                 } else {
-                     bbmin[j] = 0.;
+                    face.pass
+                };
+                let v = op.apply(*stored, st.reference);
+                *stored = (*stored & !st.write_mask) | (v & st.write_mask);
+            }
+        }
+        if depth.write {
+            for (s, stored) in self.depth[idx..idx + z.len()].iter_mut().enumerate() {
+                if depth_pass & (1 << s) != 0 {
+                    *stored = z[s];
                 }
             }
         }
     }
+}
+
+impl TileRow<'_> {
+    // Bounds of hierarchical depth block bx, by, rebuilt from the depth buffer when dirty
+    fn hiz_block(&mut self, bx : u32, by : u32, width : u32, n : usize) -> HizBlock {
+        let i = ((by - self.y_min / HIZ_BLOCK) * width.div_ceil(HIZ_BLOCK) + bx) as usize;
+        if self.hiz[i].dirty {
+            let (mut min, mut max) = (f32::MAX, f32::MIN);
+            let (x0, x1) = (bx * HIZ_BLOCK, ((bx + 1) * HIZ_BLOCK).min(width));
+            for y in by * HIZ_BLOCK..((by + 1) * HIZ_BLOCK).min(self.y_max) {
+                let idx = (x0 + (y - self.y_min) * width) as usize * n;
+                for z in self.depth[idx..idx + (x1 - x0) as usize * n].iter() {
+                    min = min.min(*z);
+                    max = max.max(*z);
+                }
+            }
+            self.hiz[i] = HizBlock { min, max, dirty : false };
+        }
+        self.hiz[i]
+    }
+
+    // Mark the blocks overlapping rect as holding stale depth bounds
+    fn touch_blocks(&mut self, rect : Rect, width : u32) {
+        let ((x0, y0), (x1, y1)) = rect;
+        let blocks_x = width.div_ceil(HIZ_BLOCK);
+        for by in y0 / HIZ_BLOCK..=(y1 - 1) / HIZ_BLOCK {
+            for bx in x0 / HIZ_BLOCK..=(x1 - 1) / HIZ_BLOCK {
+                self.hiz[((by - self.y_min / HIZ_BLOCK) * blocks_x + bx) as usize].dirty = true;
+            }
+        }
+    }
+}
+
+// Everything the back end needs that stays constant during a draw
+struct DrawContext<'a, S : Shader + ?Sized> {
+    shader : &'a S,
+    state : &'a PipelineState,
+    width : u32,
+    pattern : &'a [(i64, i64)],
+    late_z : bool,
+    // Whether hierarchical depth can reject blocks for this draw
+    hiz : bool,
+    // Depth range of the viewport, samples outside it lie beyond the near or far plane
+    depth_range : (f32, f32)
+}
+
+// Everything primitive setup needs that stays constant during a draw
+struct SetupContext<'a> {
+    state : &'a PipelineState,
+    viewport : Viewport,
+    // Pixels primitives are clipped to, min inclusive and max exclusive. This is the
+    // framebuffer cut down to the viewport and scissor rectangles, which also stands in
+    // for clipping against the sides of the view volume
+    bounds : ((u32, u32), (u32, u32)),
+    planes : [Vector4<f32>; 6]
+}
+
+impl SetupContext<'_> {
+    fn new(state : &PipelineState, width : u32, height : u32) -> SetupContext<'_> {
+        let viewport = state.viewport.unwrap_or_else(|| Viewport::new(width, height));
+        let mut min = (viewport.x.floor() as u32, viewport.y.floor() as u32);
+        let mut max = (((viewport.x + viewport.width).ceil() as u32).min(width), ((viewport.y + viewport.height).ceil() as u32).min(height));
+        if let Some(sc) = state.scissor {
+            min = (min.0.max(sc.x), min.1.max(sc.y));
+            max = (max.0.min(sc.x.saturating_add(sc.width)), max.1.min(sc.y.saturating_add(sc.height)));
+        }
+        let max = (max.0.max(min.0), max.1.max(min.1));
+        // Guard band in normalized device coordinates, halfway between the viewport and
+        // where window coordinates leave the guard band
+        let k = ((GUARD_BAND - viewport.x.abs()) / viewport.width.abs(), (GUARD_BAND - viewport.y.abs()) / viewport.height.abs());
+        SetupContext { state, viewport, bounds : (min, max), planes : clip_planes(&state.depth, k) }
+    }
+}
+
+// Sample coverage of one pixel, edge function values at the pixel centre and at
+// every sample position
+struct Coverage {
+    mask : u32,
+    center : bool,
+    center_w : [i64; 3],
+    samples : [[i64; 3]; 8]
+}
+
+// Window coordinates have to stay within this many pixels of the origin, so snapped
+// positions and edge function products can't overflow
+const GUARD_BAND : f32 = (1 << 20) as f32;
+
+fn is_finite(p : &Vector4<f32>) -> bool {
+    p.iter().all(|c| c.is_finite())
+}
+
+// Depth bias of a primitive with the given window depths and largest depth slope
+fn depth_bias(bias : &DepthBias, z : &[f32], slope : f32) -> f32 {
+    if bias.constant == 0. && bias.slope == 0. {
+        return 0.;
+    }
+    // Smallest step of f32 at the largest depth
+    let max = z.iter().fold(0f32, |m, z| m.max(z.abs()));
+    let step = f32::from_bits(max.to_bits() + 1) - max;
+    let offset = bias.constant * step + bias.slope * slope;
+    if bias.clamp != 0. {
+        offset.clamp(-bias.clamp.abs(), bias.clamp.abs())
+    } else {
+        offset
+    }
+}
+
+// Check a window space position can be snapped and rasterized. Clipped positions are
+// always in range unless the viewport itself reaches outside the guard band
+fn check_position(p : &Vector4<f32>) -> Result<(), Reject> {
+    if !is_finite(p) {
+        Err(Reject::NonFinite)
+    } else if p.x.abs() > GUARD_BAND || p.y.abs() > GUARD_BAND {
+        Err(Reject::OutOfRange)
+    } else {
+        Ok(())
+    }
+}
+
+fn setup_triangle<V>(vs : [ClipVertex<V>; 3], ctx : &SetupContext) -> Result<Triangle<V>, Reject> {
+    let (state, (lo, hi)) = (ctx.state, ctx.bounds);
+    let mut vs = vs;
+    let mut p = [(0, 0); 3];
+    for (i, v) in vs.iter().enumerate() {
+        check_position(&v.0)?;
+        p[i] = (snap(v.0.x), snap(v.0.y));
+    }
+    let mut area = (p[1].0 - p[0].0) * (p[2].1 - p[0].1) - (p[2].0 - p[0].0) * (p[1].1 - p[0].1);
+    if area == 0 {
+        return Err(Reject::Degenerate);
+    }
+    let front = is_front_facing(area, state);
+    if is_culled(front, state) {
+        return Err(Reject::Culled);
+    }
+    if area < 0 {
+        vs.swap(1, 2);
+        p.swap(1, 2);
+        area = -area;
+    }
+
+    // Generate bounding box, clamped to the drawable area
+    let min_x = p.iter().map(|p| p.0).min().unwrap();
+    let min_y = p.iter().map(|p| p.1).min().unwrap();
+    let max_x = p.iter().map(|p| p.0).max().unwrap();
+    let max_y = p.iter().map(|p| p.1).max().unwrap();
+    let x0 = (min_x >> SUBPIXEL_BITS).clamp(lo.0 as i64, hi.0 as i64) as u32;
+    let y0 = (min_y >> SUBPIXEL_BITS).clamp(lo.1 as i64, hi.1 as i64) as u32;
+    let x1 = ((max_x >> SUBPIXEL_BITS) + 1).clamp(lo.0 as i64, hi.0 as i64) as u32;
+    let y1 = ((max_y >> SUBPIXEL_BITS) + 1).clamp(lo.1 as i64, hi.1 as i64) as u32;
+    if x1 <= x0 || y1 <= y0 {
+        return Err(Reject::Clipped);
+    }
+    let edges = [Edge::new(p[1], p[2]), Edge::new(p[2], p[0]), Edge::new(p[0], p[1])];
+
+    // Depth gradient of the plane through the vertices, the snapped area is in square
+    // sub-pixels
+    let z = [vs[0].0.z, vs[1].0.z, vs[2].0.z];
+    let scale = SUBPIXEL_ONE as f32 / area as f32;
+    let dzdx = ((z[1] - z[0]) * (p[2].1 - p[0].1) as f32 - (z[2] - z[0]) * (p[1].1 - p[0].1) as f32) * scale;
+    let dzdy = ((z[2] - z[0]) * (p[1].0 - p[0].0) as f32 - (z[1] - z[0]) * (p[2].0 - p[0].0) as f32) * scale;
+    let bias = depth_bias(&state.depth_bias, &z, dzdx.abs().max(dzdy.abs()));
+    Ok(Triangle { vs, edges, area, front, conservative : state.conservative, bias, bbmin : (x0, y0), bbmax : (x1, y1) })
+}
+
+fn setup_line<V : Varyings>(vs : [ClipVertex<V>; 2], ctx : &SetupContext) -> Result<Line<V>, Reject> {
+    let (depth, (lo, hi)) = (&ctx.state.depth, ctx.bounds);
+    if !is_finite(&vs[0].0) || !is_finite(&vs[1].0) {
+        return Err(Reject::NonFinite);
+    }
+    let [a, b] = clip_line(vs, &ctx.planes).ok_or(Reject::Clipped)?;
+    let vs = [to_window(a, &ctx.viewport, depth), to_window(b, &ctx.viewport, depth)];
+    let (p0, p1) = (&vs[0].0, &vs[1].0);
+    check_position(p0)?;
+    check_position(p1)?;
+    if p0.x == p1.x && p0.y == p1.y {
+        return Err(Reject::Degenerate);
+    }
+    let x0 = (p0.x.min(p1.x).floor() as i64).clamp(lo.0 as i64, hi.0 as i64) as u32;
+    let y0 = (p0.y.min(p1.y).floor() as i64).clamp(lo.1 as i64, hi.1 as i64) as u32;
+    let x1 = (p0.x.max(p1.x).floor() as i64 + 1).clamp(lo.0 as i64, hi.0 as i64) as u32;
+    let y1 = (p0.y.max(p1.y).floor() as i64 + 1).clamp(lo.1 as i64, hi.1 as i64) as u32;
+    if x1 <= x0 || y1 <= y0 {
+        return Err(Reject::Clipped);
+    }
+    // Depth changes fastest along the major axis
+    let slope = (p1.z - p0.z).abs() / (p1.x - p0.x).abs().max((p1.y - p0.y).abs());
+    let bias = depth_bias(&ctx.state.depth_bias, &[p0.z, p1.z], slope);
+    Ok(Line { vs, bias, bbmin : (x0, y0), bbmax : (x1, y1) })
+}
+
+fn setup_point<V>(v : ClipVertex<V>, ctx : &SetupContext) -> Result<Point<V>, Reject> {
+    let (depth, (lo, hi)) = (&ctx.state.depth, ctx.bounds);
+    if !is_finite(&v.0) {
+        return Err(Reject::NonFinite);
+    }
+    if ctx.planes.iter().any(|plane| plane.dot(&v.0) < 0.) {
+        return Err(Reject::Clipped);
+    }
+    let v = to_window(v, &ctx.viewport, depth);
+    check_position(&v.0)?;
+    let (x, y) = (v.0.x.floor(), v.0.y.floor());
+    if !(lo.0 as f32..hi.0 as f32).contains(&x) || !(lo.1 as f32..hi.1 as f32).contains(&y) {
+        return Err(Reject::Clipped);
+    }
+    let bias = depth_bias(&ctx.state.depth_bias, &[v.0.z], 0.);
+    Ok(Point { v, bias, x : x as u32, y : y as u32 })
+}
+
+// Clip a triangle and set up the pieces as a fan around the first vertex, the
+// clipped polygon is always convex
+fn assemble_triangle<V : Varyings>(vs : [ClipVertex<V>; 3], ctx : &SetupContext, out : &mut Vec<Primitive<V>>, stats : &mut DrawStats) {
+    let depth = &ctx.state.depth;
+    if !vs.iter().all(|v| is_finite(&v.0)) {
+        stats.reject(Reject::NonFinite);
+        return;
+    }
+    let poly = clip_triangle(vs, &ctx.planes);
+    if poly.is_empty() {
+        stats.reject(Reject::Clipped);
+        return;
+    }
+    let mut poly = poly.into_iter().map(|v| to_window(v, &ctx.viewport, depth));
+    let first = poly.next().unwrap();
+    let mut prev = poly.next().unwrap();
+    for cur in poly {
+        let t = [first.clone(), prev, cur.clone()];
+        match setup_triangle(t, ctx) {
+            Ok(tri) => out.push(Primitive::Triangle(tri)),
+            Err(reason) => stats.reject(reason)
+        }
+        prev = cur;
+    }
+}
+
+// Vertex stage. Every vertex the draw references is shaded exactly once, split into
+// contiguous chunks across threads, and shared by all the primitives using it
+fn shade_vertices<S : Shader + ?Sized>(count : usize, indices : Option<&[u32]>, shader : &S, threads : usize) -> Vec<Option<ClipVertex<S::Varyings>>> {
+    let used = match indices {
+        Some(indices) => {
+            let len = indices.iter().max().map_or(0, |&i| i as usize + 1);
+            let mut used = vec![false; len];
+            for &i in indices {
+                used[i as usize] = true;
+            }
+            used
+        },
+        None => vec![true; count]
+    };
+    let chunk = used.len().div_ceil(threads).max(1);
+    thread::scope(|s| {
+        let handles : Vec<_> = used.chunks(chunk).enumerate().map(|(c, used)| {
+            s.spawn(move || used.iter().enumerate().map(|(i, &used)| {
+                if used {Some(shader.vertex((c * chunk + i) as u32))} else {None}
+            }).collect::<Vec<_>>())
+        }).collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    })
+}
+
+// Primitive assembly, clipping and setup for a range of input primitives. Vertex i of
+// the draw is indices[i] for indexed draws and i otherwise
+fn process_primitives<V : Varyings>(range : std::ops::Range<usize>, indices : Option<&[u32]>, verts : &[Option<ClipVertex<V>>], ctx : &SetupContext) -> (Vec<Primitive<V>>, DrawStats) {
+    let vertex = |i : u32| {
+        let index = indices.map_or(i, |indices| indices[i as usize]);
+        verts[index as usize].clone().unwrap()
+    };
+    let mut prims = Vec::new();
+    let mut stats = DrawStats::default();
+    for k in range {
+        let k = k as u32;
+        match ctx.state.topology {
+            PrimitiveTopology::TriangleList => {
+                let vs = [vertex(k * 3), vertex(k * 3 + 1), vertex(k * 3 + 2)];
+                assemble_triangle(vs, ctx, &mut prims, &mut stats);
+            },
+            PrimitiveTopology::TriangleStrip => {
+                // Every other triangle of a strip is flipped to keep the winding of the
+                // first, keeping vertex k first as it provokes the triangle
+                let vs = if k % 2 == 1 {
+                    [vertex(k), vertex(k + 2), vertex(k + 1)]
+                } else {
+                    [vertex(k), vertex(k + 1), vertex(k + 2)]
+                };
+                assemble_triangle(vs, ctx, &mut prims, &mut stats);
+            },
+            PrimitiveTopology::TriangleFan => {
+                // Vertex k + 1 provokes the triangle, the centre comes last
+                let vs = [vertex(k + 1), vertex(k + 2), vertex(0)];
+                assemble_triangle(vs, ctx, &mut prims, &mut stats);
+            },
+            PrimitiveTopology::LineList | PrimitiveTopology::LineStrip => {
+                let vs = if ctx.state.topology == PrimitiveTopology::LineList {
+                    [vertex(k * 2), vertex(k * 2 + 1)]
+                } else {
+                    [vertex(k), vertex(k + 1)]
+                };
+                match setup_line(vs, ctx) {
+                    Ok(line) => prims.push(Primitive::Line(line)),
+                    Err(reason) => stats.reject(reason)
+                }
+            },
+            PrimitiveTopology::PointList => {
+                match setup_point(vertex(k), ctx) {
+                    Ok(point) => prims.push(Primitive::Point(point)),
+                    Err(reason) => stats.reject(reason)
+                }
+            }
+        }
+    }
+    (prims, stats)
+}
+
+impl<V : Varyings> Triangle<V> {
+    fn barycentric(&self, w : [i64; 3]) -> (f32, f32, f32) {
+        let area = self.area as f32;
+        (w[0] as f32 / area, w[1] as f32 / area, w[2] as f32 / area)
+    }
+
+    // Quad of pixel x, y given the edge functions at its centre
+    fn quad(&self, x : u32, y : u32, w : [i64; 3]) -> Quad<'_, V> {
+        let e = &self.edges;
+        let vs = &self.vs;
+        pixel_quad(x, y, (vs[0].0.w, vs[1].0.w, vs[2].0.w), [&vs[0].1, &vs[1].1, &vs[2].1], |dx, dy| {
+            let step = |i : usize| w[i] + (e[i].a * dx + e[i].b * dy) * SUBPIXEL_ONE;
+            self.barycentric([step(0), step(1), step(2)])
+        })
+    }
+
+    fn inside(&self, w : [i64; 3]) -> bool {
+        let e = &self.edges;
+        w[0] + e[0].bias >= 0 && w[1] + e[1].bias >= 0 && w[2] + e[2].bias >= 0
+    }
+
+    // Fraction of the area of pixel x, y inside the triangle, found by clipping the
+    // pixel square against each edge
+    fn pixel_coverage(&self, x : u32, y : u32) -> f32 {
+        let (x, y) = ((x as i64 * SUBPIXEL_ONE) as f64, (y as i64 * SUBPIXEL_ONE) as f64);
+        let one = SUBPIXEL_ONE as f64;
+        let mut poly = vec![(x, y), (x + one, y), (x + one, y + one), (x, y + one)];
+        for e in self.edges.iter() {
+            let eval = |p : (f64, f64)| e.a as f64 * p.0 + e.b as f64 * p.1 + e.c as f64;
+            let mut out = Vec::with_capacity(poly.len() + 1);
+            for i in 0..poly.len() {
+                let (cur, next) = (poly[i], poly[(i + 1) % poly.len()]);
+                let (d0, d1) = (eval(cur), eval(next));
+                if d0 >= 0. {
+                    out.push(cur);
+                }
+                if (d0 >= 0.) != (d1 >= 0.) {
+                    let t = d0 / (d0 - d1);
+                    out.push((cur.0 + (next.0 - cur.0) * t, cur.1 + (next.1 - cur.1) * t));
+                }
+            }
+            poly = out;
+        }
+        let mut area = 0.;
+        for i in 0..poly.len() {
+            let (p, q) = (poly[i], poly[(i + 1) % poly.len()]);
+            area += p.0 * q.1 - q.0 * p.1;
+        }
+        (area / 2. / (one * one)) as f32
+    }
+}
+
+// Walk the pixels of x0..x1, y0..y1 where at least one sample of the pattern is covered.
+// Conservative triangles instead cover every sample of the pixels they touch at all.
+// Edge functions are stepped incrementally from pixel centre to pixel centre
+fn for_each_covered<V : Varyings, F : FnMut(u32, u32, &Coverage)>(tri : &Triangle<V>, pattern : &[(i64, i64)], x0 : u32, x1 : u32, y0 : u32, y1 : u32, mut f : F) {
+    let e = &tri.edges;
+    // A pixel square touches an edge's half plane when the edge function at its centre
+    // is within half a pixel along both axes of the edge
+    let reach = [0, 1, 2].map(|i| (e[i].a.abs() + e[i].b.abs()) * SUBPIXEL_ONE / 2);
+    let all = (1 << pattern.len()) - 1;
+    // Sample offsets are in 1/16 pixel units
+    let unit = SUBPIXEL_ONE / 16;
+    let mut deltas = [[0; 3]; 8];
+    for (s, (ox, oy)) in pattern.iter().enumerate() {
+        for i in 0..3 {
+            deltas[s][i] = (e[i].a * ox + e[i].b * oy) * unit;
+        }
+    }
+    let half = SUBPIXEL_ONE / 2;
+    let (px, py) = ((x0 as i64) * SUBPIXEL_ONE + half, (y0 as i64) * SUBPIXEL_ONE + half);
+    let mut row = [e[0].eval(px, py), e[1].eval(px, py), e[2].eval(px, py)];
+    let mut cov = Coverage { mask : 0, center : false, center_w : row, samples : [[0; 3]; 8] };
+    for y in y0..y1 {
+        let mut w = row;
+        for x in x0..x1 {
+            cov.mask = 0;
+            for (s, d) in deltas[..pattern.len()].iter().enumerate() {
+                cov.samples[s] = [w[0] + d[0], w[1] + d[1], w[2] + d[2]];
+                if tri.inside(cov.samples[s]) {
+                    cov.mask |= 1 << s;
+                }
+            }
+            if tri.conservative && (0..3).all(|i| w[i] + reach[i] >= 0) {
+                cov.mask = all;
+            }
+            if cov.mask != 0 {
+                cov.center = tri.inside(w);
+                cov.center_w = w;
+                f(x, y, &cov);
+            }
+            for i in 0..3 {
+                w[i] += e[i].a * SUBPIXEL_ONE;
+            }
+        }
+        for i in 0..3 {
+            row[i] += e[i].b * SUBPIXEL_ONE;
+        }
+    }
+}
+
+// The 2x2 quad holding pixel x, y. bc gives the barycentrics of the pixel dx, dy away,
+// and is called for every lane whether or not the primitive covers it
+fn pixel_quad<'a, V : Varyings, F : Fn(i64, i64) -> (f32, f32, f32)>(x : u32, y : u32, ws : (f32, f32, f32), vs : [&'a V; 3], bc : F) -> Quad<'a, V> {
+    let (lx, ly) = ((x & 1) as i64, (y & 1) as i64);
+    let mut bcs = [(0., 0., 0.); 4];
+    for (lane, b) in bcs.iter_mut().enumerate() {
+        *b = bc((lane & 1) as i64 - lx, (lane >> 1) as i64 - ly);
+    }
+    Quad::new(bcs, (lx + 2 * ly) as usize, ws, vs)
+}
+
+// Stencil and depth tests, fragment shading and colour writes for the samples in mask
+// of one pixel, z holds the depth of every sample. The fragment colour is stored to
+// every sample that passes both tests. Unless the shader asks for late-Z the tests
+// run first and pixels without a visible sample are never shaded. Returns whether the
+// shader ran and the samples that were written
+fn shade_pixel<S : Shader + ?Sized, F : FnOnce() -> FragmentOutput>(ctx : &DrawContext<S>, row : &mut TileRow, idx : usize, mask : u32, z : &mut [f32], face : &StencilFace, shade : F) -> (bool, u32) {
+    let state = ctx.state;
+    let mut pass = (mask, mask);
+    if !ctx.late_z {
+        pass = row.test(idx, mask, z, face, state);
+        if pass.1 == 0 {
+            row.update(idx, mask, pass, z, face, state);
+            return (false, 0);
+        }
+    }
+
+    let out = shade();
+    if out.drop {
+        return (true, 0);
+    }
+
+    if ctx.late_z {
+        if let Some(depth) = out.depth {
+            for z in z.iter_mut() {
+                *z = depth;
+            }
+        }
+        pass = row.test(idx, mask, z, face, state);
+    }
+    row.update(idx, mask, pass, z, face, state);
+    for (color, target) in out.colors.iter().zip(row.colors.iter_mut()) {
+        for s in 0..z.len() {
+            if pass.1 & (1 << s) != 0 {
+                let dst = target.load(idx + s);
+                target.store(idx + s, &state.blend.blend(color, &dst));
+            }
+        }
+    }
+    (true, pass.1)
+}
+
+// Rasterize the part of a triangle inside rect, which lies within its bounding box and
+// a single tile row. The fragment shader runs once per pixel
+fn rasterize_triangle<S : Shader + ?Sized>(tri : &Triangle<S::Varyings>, ctx : &DrawContext<S>, rect : Rect, row : &mut TileRow, stats : &mut DrawStats) {
+    let (shader, state, width, pattern) = (ctx.shader, ctx.state, ctx.width, ctx.pattern);
+    let n = pattern.len();
+    let vs = &tri.vs;
+    let face = if tri.front {&state.stencil.front} else {&state.stencil.back};
+    let ((x0, y0), (x1, y1)) = rect;
+    // Conservative coverage extrapolates depth to samples outside the triangle, keep
+    // it within the depth of the vertices
+    let z_lo = vs[0].0.z.min(vs[1].0.z).min(vs[2].0.z);
+    let z_hi = vs[0].0.z.max(vs[1].0.z).max(vs[2].0.z);
+
+    for_each_covered(tri, pattern, x0, x1, y0, y1, |x, y, cov| {
+        let pixel_idx = (x + (y - row.y_min) * width) as usize * n;
 
-    if bbmin[0] < 0. {bbmin[0] = 0.}
-    if bbmin[1] < 0. {bbmin[1] = 0.}
-    if bbmax[0] < 0. {bbmax[0] = 0.}
-    if bbmax[1] < 0. {bbmax[1] = 0.}
-    if bbmax[0] <= bbmin[0] || bbmax[1] <= bbmin[1] {return}
+        // Depth is affine in screen space, evaluate it at every covered sample
+        let mut mask = cov.mask;
+        let mut z = [0.; 8];
+        for (s, z) in z[..n].iter_mut().enumerate() {
+            let bc = tri.barycentric(cov.samples[s]);
+            *z = (bc.0 * vs[0].0.z + bc.1 * vs[1].0.z + bc.2 * vs[2].0.z).clamp(z_lo, z_hi);
+            if !(ctx.depth_range.0..=ctx.depth_range.1).contains(z) {
+                mask &= !(1 << s);
+            }
+            *z += tri.bias;
+        }
+        if mask == 0 {
+            return;
+        }
 
-    // Rasterize triangle pixels inside bounding box
-    for x in f32::floor(bbmin[0]) as u32..f32::ceil(bbmax[0]) as u32{
-         for y in f32::floor(bbmin[1]) as u32..f32::ceil(bbmax[1]) as u32 {
-            let x_proper = x as f32 + 0.5;
-            let y_proper = y as f32 + 0.5;
-            let bc = baycentric2d(x_proper, y_proper, (vs[0].0, vs[1].0, vs[2].0));
+        // Shade at the pixel centre, or at the first covered sample when the centre
+        // lies outside the triangle so varyings are never extrapolated. Conservative
+        // pixels may have no sample inside, their barycentrics are clamped instead
+        let (shaded, passed) = shade_pixel(ctx, row, pixel_idx, mask, &mut z[..n], face, || {
+            let bc = if cov.center {
+                tri.barycentric(cov.center_w)
+            } else if tri.conservative {
+                let bc = tri.barycentric(cov.center_w);
+                let bc = (bc.0.max(0.), bc.1.max(0.), bc.2.max(0.));
+                let sum = bc.0 + bc.1 + bc.2;
+                (bc.0 / sum, bc.1 / sum, bc.2 / sum)
+            } else {
+                tri.barycentric(cov.samples[mask.trailing_zeros() as usize])
+            };
+            let coverage = if tri.conservative {
+                tri.pixel_coverage(x, y)
+            } else {
+                cov.mask.count_ones() as f32 / n as f32
+            };
+            let input = FragmentInput { quad : tri.quad(x, y, cov.center_w), sample_mask : cov.mask, coverage };
+            let v = Varyings::interpolate([&vs[0].1, &vs[1].1, &vs[2].1], &Barycentrics::new(bc, (vs[0].0.w, vs[1].0.w, vs[2].0.w)));
+            shader.fragment(&v, &input)
+        });
+        stats.fragment(shaded, passed);
+    });
+}
+
+// Lines are one pixel wide. They step along their major axis and cover the pixel each
+// centre row or column crosses, with the last pixel left out so the segments of a
+// strip never touch a pixel twice. Every sample of a covered pixel is covered, and
+// the fragment stage sees the line as a triangle with barycentrics (1 - t, t, 0)
+fn rasterize_line<S : Shader + ?Sized>(line : &Line<S::Varyings>, ctx : &DrawContext<S>, rect : Rect, row : &mut TileRow, stats : &mut DrawStats) {
+    let (state, width, n) = (ctx.state, ctx.width, ctx.pattern.len());
+    let (a, b) = (&line.vs[0], &line.vs[1]);
+    let (pa, pb) = (&a.0, &b.0);
+    let ((x0, y0), (x1, y1)) = rect;
+    let x_major = (pb.x - pa.x).abs() >= (pb.y - pa.y).abs();
+    // Major and minor coordinates of both ends, and the major range of the tile
+    let (u0, u1, m0, m1, lo, hi) = if x_major {
+        (pa.x, pb.x, pa.y, pb.y, x0, x1)
+    } else {
+        (pa.y, pb.y, pa.x, pb.x, y0, y1)
+    };
+    if u0 == u1 {
+        return;
+    }
+    // Pixels whose centre c + 0.5 lies between the ends, start included and end excluded
+    let (first, last) = if u0 < u1 {
+        ((u0 - 0.5).ceil(), (u1 - 0.5).ceil())
+    } else {
+        ((u1 - 0.5).floor() + 1., (u0 - 0.5).floor() + 1.)
+    };
+    let first = (first as i64).clamp(lo as i64, hi as i64);
+    let last = (last as i64).clamp(lo as i64, hi as i64);
+
+    for c in first..last {
+        let t = (c as f32 + 0.5 - u0) / (u1 - u0);
+        let m = (m0 + (m1 - m0) * t).floor();
+        let (x, y) = if x_major {(c as f32, m)} else {(m, c as f32)};
+        if !(x0 as f32..x1 as f32).contains(&x) || !(y0 as f32..y1 as f32).contains(&y) {
+            continue;
+        }
+        let z = pa.z + (pb.z - pa.z) * t;
+        if !(ctx.depth_range.0..=ctx.depth_range.1).contains(&z) {
+            continue;
+        }
+        let pixel_idx = (x as u32 + (y as u32 - row.y_min) * width) as usize * n;
+        let mut z = [z + line.bias; 8];
+        let (shaded, passed) = shade_pixel(ctx, row, pixel_idx, (1 << n) - 1, &mut z[..n], &state.stencil.front, || {
+            // Along a line t only changes with the major axis
+            let dt = 1. / (u1 - u0);
+            let quad = pixel_quad(x as u32, y as u32, (pa.w, pb.w, pb.w), [&a.1, &b.1, &b.1], |dx, dy| {
+                let t = t + (if x_major {dx} else {dy}) as f32 * dt;
+                (1. - t, t, 0.)
+            });
+            let input = FragmentInput { quad, sample_mask : (1 << n) - 1, coverage : 1. };
+            let v = Varyings::interpolate([&a.1, &b.1, &b.1], &Barycentrics::new((1. - t, t, 0.), (pa.w, pb.w, pb.w)));
+            ctx.shader.fragment(&v, &input)
+        });
+        stats.fragment(shaded, passed);
+    }
+}
 
-            // Not in triangle
-            if bc.0 < 0. || bc.1 < 0. || bc.2 < 0. || x * y > img.width() * img.height() {
+// Points cover every sample of a single pixel
+fn rasterize_point<S : Shader + ?Sized>(point : &Point<S::Varyings>, ctx : &DrawContext<S>, rect : Rect, row : &mut TileRow, stats : &mut DrawStats) {
+    let (state, width, n) = (ctx.state, ctx.width, ctx.pattern.len());
+    let (p, vary) = (&point.v.0, &point.v.1);
+    let ((x0, y0), (x1, y1)) = rect;
+    if !(x0..x1).contains(&point.x) || !(y0..y1).contains(&point.y) || !(ctx.depth_range.0..=ctx.depth_range.1).contains(&p.z) {
+        return;
+    }
+    let pixel_idx = (point.x + (point.y - row.y_min) * width) as usize * n;
+    let mut z = [p.z + point.bias; 8];
+    let (shaded, passed) = shade_pixel(ctx, row, pixel_idx, (1 << n) - 1, &mut z[..n], &state.stencil.front, || {
+        let quad = pixel_quad(point.x, point.y, (p.w, p.w, p.w), [vary, vary, vary], |_, _| (1., 0., 0.));
+        let input = FragmentInput { quad, sample_mask : (1 << n) - 1, coverage : 1. };
+        ctx.shader.fragment(vary, &input)
+    });
+    stats.fragment(shaded, passed);
+}
+
+// Whether every sample of a block fails the depth test against fragments with depth
+// in z_lo..=z_hi
+fn is_occluded(compare : CompareFunc, z_lo : f32, z_hi : f32, block : &HizBlock) -> bool {
+    match compare {
+        CompareFunc::Never => true,
+        CompareFunc::Greater => z_hi <= block.min,
+        CompareFunc::GreaterEqual => z_hi < block.min,
+        CompareFunc::Less => z_lo >= block.max,
+        CompareFunc::LessEqual => z_lo > block.max,
+        _ => false
+    }
+}
+
+fn draw_primitive<S : Shader + ?Sized>(prim : &Primitive<S::Varyings>, ctx : &DrawContext<S>, rect : Rect, row : &mut TileRow, stats : &mut DrawStats) {
+    match prim {
+        Primitive::Triangle(tri) => rasterize_triangle(tri, ctx, rect, row, stats),
+        Primitive::Line(line) => rasterize_line(line, ctx, rect, row, stats),
+        Primitive::Point(point) => rasterize_point(point, ctx, rect, row, stats)
+    }
+}
+
+// Rasterize the part of a primitive inside the tile x0..x1 of a tile row. With
+// hierarchical depth the tile is walked in blocks, and blocks whose stored depth
+// already hides the whole primitive are skipped
+fn draw_in_tile<S : Shader + ?Sized>(prim : &Primitive<S::Varyings>, ctx : &DrawContext<S>, x0 : u32, x1 : u32, row : &mut TileRow, stats : &mut DrawStats) {
+    let (bbmin, bbmax) = prim.bounds();
+    let lo = (x0.max(bbmin.0), row.y_min.max(bbmin.1));
+    let hi = (x1.min(bbmax.0), row.y_max.min(bbmax.1));
+    if hi.0 <= lo.0 || hi.1 <= lo.1 {
+        return;
+    }
+    let write = ctx.state.depth.write;
+    if !ctx.hiz {
+        draw_primitive(prim, ctx, (lo, hi), row, stats);
+        if write {
+            row.touch_blocks((lo, hi), ctx.width);
+        }
+        return;
+    }
+    let (z_lo, z_hi) = prim.depth_bounds();
+    for by in lo.1 / HIZ_BLOCK..=(hi.1 - 1) / HIZ_BLOCK {
+        for bx in lo.0 / HIZ_BLOCK..=(hi.0 - 1) / HIZ_BLOCK {
+            let block = row.hiz_block(bx, by, ctx.width, ctx.pattern.len());
+            if is_occluded(ctx.state.depth.compare, z_lo, z_hi, &block) {
+                stats.hiz_culled += 1;
                 continue;
             }
-            // In triangle
-            // Get interpolated z value
-            let w_reci = bc.0 * vs[0].0.w + bc.1 * vs[1].0.w + bc.2 * vs[2].0.w;
-            let z_interpolated = (bc.0 * vs[0].0.z * vs[0].0.w + bc.1 * vs[1].0.z * vs[1].0.w + bc.2 * vs[2].0.z * vs[2].0.w) / w_reci;
-            if z_interpolated > 1. || z_interpolated < -1. {continue};
-            let z_buffer_idx = (x + y * img.width()) as usize;
-            
-            let (color, drop) = (*shader).fragment(bc, (vs[0].0.w, vs[1].0.w, vs[2].0.w), (&vs[0].1, &vs[1].1, &vs[2].1));
+            let min = (lo.0.max(bx * HIZ_BLOCK), lo.1.max(by * HIZ_BLOCK));
+            let max = (hi.0.min((bx + 1) * HIZ_BLOCK), hi.1.min((by + 1) * HIZ_BLOCK));
+            draw_primitive(prim, ctx, (min, max), row, stats);
+            if write {
+                row.touch_blocks((min, max), ctx.width);
+            }
+        }
+    }
+}
+
+fn thread_count(state : &PipelineState) -> usize {
+    if state.threads > 0 {
+        state.threads
+    } else {
+        thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+    }
+}
+
+// Draw vertices 0..count, assembled into primitives according to state.topology
+pub fn rasterize<S : Shader + ?Sized>(count : usize, shader : &S, state : &PipelineState, fb : &mut Framebuffer) -> DrawStats {
+    draw(count, None, shader, state, fb)
+}
 
-            if z_buffer[z_buffer_idx]  < z_interpolated && !drop {
-                z_buffer[z_buffer_idx] = z_interpolated;
-                // flip y value here
-                img.put_pixel(x as u32, img.height() - y - 1 as u32, color);
+// Draw the vertices listed in an index buffer
+pub fn rasterize_indexed<S : Shader + ?Sized>(indices : &[u32], shader : &S, state : &PipelineState, fb : &mut Framebuffer) -> DrawStats {
+    draw(indices.len(), Some(indices), shader, state, fb)
+}
+
+fn draw<S : Shader + ?Sized>(count : usize, indices : Option<&[u32]>, shader : &S, state : &PipelineState, fb : &mut Framebuffer) -> DrawStats {
+    assert!(fb.colors.len() <= MAX_COLOR_ATTACHMENTS);
+    let (width, height) = (fb.width, fb.height);
+    // An empty framebuffer has no pixels to draw and no rows to split into tiles
+    if width == 0 || height == 0 {
+        return DrawStats::default();
+    }
+    let pattern = fb.samples.pattern();
+    let threads = thread_count(state);
+
+    // Front end, primitives are split into contiguous chunks and concatenated in order
+    let mut stats = DrawStats::default();
+    let setup = &SetupContext::new(state, width, height);
+    let start = Instant::now();
+    let verts = &shade_vertices(count, indices, shader, threads);
+    stats.vertices_shaded = verts.iter().filter(|v| v.is_some()).count() as u64;
+    stats.vertex_time = start.elapsed();
+
+    let start = Instant::now();
+    let len = state.topology.primitive_count(count);
+    let chunk = len.div_ceil(threads).max(1);
+    let mut prims = Vec::new();
+    thread::scope(|s| {
+        let handles : Vec<_> = (0..len).step_by(chunk).map(|start| {
+            let range = start..(start + chunk).min(len);
+            s.spawn(move || process_primitives(range, indices, verts, setup))
+        }).collect();
+        for h in handles {
+            let (p, st) = h.join().unwrap();
+            prims.extend(p);
+            stats += st;
+        }
+    });
+    stats.primitives_in = len as u64;
+    stats.primitives_out = prims.len() as u64;
+    stats.setup_time = start.elapsed();
+
+    // Bin primitives into tiles
+    let start = Instant::now();
+    let tiles_x = width.div_ceil(TILE_SIZE);
+    let tiles_y = height.div_ceil(TILE_SIZE);
+    let mut bins : Vec<Vec<u32>> = vec![Vec::new(); (tiles_x * tiles_y) as usize];
+    for (i, prim) in prims.iter().enumerate() {
+        let (bbmin, bbmax) = prim.bounds();
+        let (tx0, tx1) = (bbmin.0 / TILE_SIZE, (bbmax.0 - 1) / TILE_SIZE);
+        let (ty0, ty1) = (bbmin.1 / TILE_SIZE, (bbmax.1 - 1) / TILE_SIZE);
+        for ty in ty0..=ty1 {
+            for tx in tx0..=tx1 {
+                bins[(ty * tiles_x + tx) as usize].push(i as u32);
             }
         }
-    } 
+    }
+
+    stats.binning_time = start.elapsed();
+
+    // Back end, hand out rows of tiles to the worker threads, each counting fragments
+    // on its own
+    let start = Instant::now();
+    let rows = fb.tile_rows(TILE_SIZE).into_iter().enumerate().map(|(k, row)| (k as u32, row));
+    let queue = Mutex::new(rows);
+    let (near, far) = (setup.viewport.max_depth, setup.viewport.min_depth);
+    // Shaders that write depth make the interpolated depth meaningless for culling, and
+    // stencil operations have to see the fragments that fail the depth test
+    let late_z = shader.late_z();
+    let hiz = state.depth.hierarchical && !late_z && !state.stencil.enabled;
+    let ctx = DrawContext { shader, state, width, pattern, late_z, hiz, depth_range : (near.min(far), near.max(far)) };
+    thread::scope(|s| {
+        let handles : Vec<_> = (0..threads).map(|_| s.spawn(|| {
+            let mut stats = DrawStats::default();
+            loop {
+                let next = queue.lock().unwrap().next();
+                let (ty, mut row) = match next {
+                    Some(r) => r,
+                    None => break
+                };
+                for tx in 0..tiles_x {
+                    let x0 = tx * TILE_SIZE;
+                    let x1 = (x0 + TILE_SIZE).min(width);
+                    for &i in bins[(ty * tiles_x + tx) as usize].iter() {
+                        draw_in_tile(&prims[i as usize], &ctx, x0, x1, &mut row, &mut stats);
+                    }
+                }
+            }
+            stats
+        })).collect();
+        for h in handles {
+            stats += h.join().unwrap();
+        }
+    });
+    stats.raster_time = start.elapsed();
+    stats
 }
 
-pub fn rasterize<'a>(len : usize, shader : &Box<dyn Shader + '_>, z_buf : &mut Vec<f32>, img : &mut RgbImage) {
-    for i in 0..len {
-        let v0 = (*shader).vertex(i as u32, 0);
-        let v1 = (*shader).vertex(i as u32, 1);
-        let v2 = (*shader).vertex(i as u32, 2);
-        let t = [v0,v1,v2];
-        rasterize_triangle(t, &shader, z_buf, img);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framebuffer::SampleCount;
+    use crate::stats::OcclusionQuery;
+    use crate::varyings::{Flat, NoPerspective};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn screen_vertex(x : f32, y : f32) -> ClipVertex<()> {
+        (Vector4::new(x, y, 0., 1.), ())
+    }
+
+    // Squares centred on the screen with a half size and depth in normalized device
+    // coordinates, two triangles each
+    struct SquareShader(Vec<(f32, f32)>);
+
+    impl Shader for SquareShader {
+        type Varyings = ();
+
+        fn vertex(&self, i : u32) -> (Vector4<f32>, ()) {
+            let (x, y) = [(-1., -1.), (1., -1.), (1., 1.), (-1., -1.), (1., 1.), (-1., 1.)][i as usize % 6];
+            let (size, z) = self.0[i as usize / 6];
+            (Vector4::new(x * size, y * size, z, 1.), ())
+        }
+
+        fn fragment(&self, _v : &(), _input : &FragmentInput<()>) -> FragmentOutput {
+            FragmentOutput::rgba(Vector4::new(1., 1., 1., 1.))
+        }
+    }
+
+    // Triangles with a clip space position and an RGBA colour per vertex
+    struct ColorShader(Vec<(Vector4<f32>, Vector4<f32>)>);
+
+    impl Shader for ColorShader {
+        type Varyings = Vector4<f32>;
+
+        fn vertex(&self, i : u32) -> (Vector4<f32>, Vector4<f32>) {
+            self.0[i as usize]
+        }
+
+        fn fragment(&self, v : &Vector4<f32>, _input : &FragmentInput<Vector4<f32>>) -> FragmentOutput {
+            FragmentOutput::rgba(*v)
+        }
     }
-}
\ No newline at end of file
+
+    // Overlapping triangles of random size, depth, winding and translucent colour, some
+    // reaching past the edges of the screen
+    fn random_scene(seed : u64, triangles : usize) -> ColorShader {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut vs = Vec::new();
+        for _ in 0..triangles * 3 {
+            let p = Vector4::new(rng.gen_range(-1.2, 1.2), rng.gen_range(-1.2, 1.2), rng.gen_range(-0.9, 0.9), 1.);
+            let c = Vector4::new(rng.gen(), rng.gen(), rng.gen(), rng.gen_range(0.2, 1.));
+            vs.push((p, c));
+        }
+        ColorShader(vs)
+    }
+
+    // The resolved colour and the raw depth of a 64x64 framebuffer after drawing shader
+    fn render_scene(shader : &ColorShader, state : &PipelineState, samples : SampleCount) -> (Vec<f32>, Vec<f32>) {
+        let mut fb = Framebuffer::new(64, 64, samples).with_color(crate::framebuffer::Format::Rgba32F);
+        fb.clear_depth_for(&state.depth);
+        rasterize(shader.0.len(), shader, state, &mut fb);
+        (fb.resolve_f32(0), fb.depth().to_vec())
+    }
+
+    // A jittered grid split into triangles along random diagonals with random winding.
+    // Some vertices sit exactly on pixel centres so edges pass through sample points
+    #[test]
+    fn shared_edges_are_covered_once() {
+        let (width, height) = (64, 64);
+        let n = 12;
+        let (lo, hi) = (8., 56.);
+        let step = (hi - lo) / n as f32;
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut grid = vec![(0., 0.); (n + 1) * (n + 1)];
+        for j in 0..=n {
+            for i in 0..=n {
+                let mut x = lo + i as f32 * step;
+                let mut y = lo + j as f32 * step;
+                if i > 0 && i < n && j > 0 && j < n {
+                    x += rng.gen_range(-0.4, 0.4) * step;
+                    y += rng.gen_range(-0.4, 0.4) * step;
+                    if rng.gen_bool(0.3) {
+                        x = x.floor() + 0.5;
+                        y = y.floor() + 0.5;
+                    }
+                }
+                grid[j * (n + 1) + i] = (x, y);
+            }
+        }
+
+        let state = PipelineState::default();
+        let ctx = SetupContext::new(&state, width, height);
+        let mut coverage = vec![0u32; (width * height) as usize];
+        for j in 0..n {
+            for i in 0..n {
+                let c = [j * (n + 1) + i, j * (n + 1) + i + 1, (j + 1) * (n + 1) + i, (j + 1) * (n + 1) + i + 1];
+                let mut tris = if rng.gen_bool(0.5) {
+                    [[c[0], c[1], c[3]], [c[0], c[3], c[2]]]
+                } else {
+                    [[c[0], c[1], c[2]], [c[1], c[3], c[2]]]
+                };
+                for t in tris.iter_mut() {
+                    if rng.gen_bool(0.5) {
+                        t.swap(1, 2);
+                    }
+                    let vs = [screen_vertex(grid[t[0]].0, grid[t[0]].1),
+                        screen_vertex(grid[t[1]].0, grid[t[1]].1),
+                        screen_vertex(grid[t[2]].0, grid[t[2]].1)];
+                    if let Ok(tri) = setup_triangle(vs, &ctx) {
+                        let pattern = SampleCount::X1.pattern();
+                        for_each_covered(&tri, pattern, tri.bbmin.0, tri.bbmax.0, tri.bbmin.1, tri.bbmax.1, |x, y, _| {
+                            coverage[(x + y * width) as usize] += 1;
+                        });
+                    }
+                }
+            }
+        }
+
+        for y in 0..height {
+            for x in 0..width {
+                let inside = (x as f32 + 0.5) > lo && (x as f32 + 0.5) < hi && (y as f32 + 0.5) > lo && (y as f32 + 0.5) < hi;
+                let expected = if inside {1} else {0};
+                assert_eq!(coverage[(x + y * width) as usize], expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn occlusion_query_counts_visible_samples() {
+        let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgb8);
+        let state = PipelineState::default();
+        let stats = rasterize(6, &SquareShader(vec![(1., 0.5)]), &state, &mut fb);
+        assert_eq!((stats.vertices_shaded, stats.primitives_in, stats.primitives_out), (6, 2, 2));
+        assert_eq!(stats.fragments_generated, 64 * 64);
+        assert_eq!(stats.samples_passed, 64 * 64);
+
+        // A square behind the first is hidden, one in front covers 32x32 pixels
+        let mut probe = state;
+        probe.depth.write = false;
+        let mut query = OcclusionQuery::new();
+        query.begin();
+        query.record(&rasterize(6, &SquareShader(vec![(0.5, 0.)]), &probe, &mut fb));
+        query.end();
+        assert!(!query.any_samples_passed());
+        query.begin();
+        let stats = rasterize(6, &SquareShader(vec![(0.5, 0.9)]), &probe, &mut fb);
+        query.record(&stats);
+        query.end();
+        assert_eq!(query.samples_passed(), 32 * 32);
+        assert_eq!((stats.fragments_shaded, stats.fragments_passed), (32 * 32, 32 * 32));
+    }
+
+    #[derive(Clone)]
+    struct IdVaryings {
+        id : Flat<u32>,
+        x : NoPerspective<f32>
+    }
+
+    crate::varyings!(IdVaryings { id, x });
+
+    // Outputs the vertex id of the provoking vertex and the window x of the fragment,
+    // interpolated linearly in screen space from the vertices
+    struct IdShader(Vec<Vector4<f32>>);
+
+    impl Shader for IdShader {
+        type Varyings = IdVaryings;
+
+        fn vertex(&self, i : u32) -> (Vector4<f32>, IdVaryings) {
+            let p = self.0[i as usize];
+            (p, IdVaryings { id : Flat(i), x : NoPerspective((p.x / p.w + 1.) * 32.) })
+        }
+
+        fn fragment(&self, v : &IdVaryings, _input : &FragmentInput<IdVaryings>) -> FragmentOutput {
+            FragmentOutput::rgba(Vector4::new(v.id.0 as f32, v.x.0, 0., 1.))
+        }
+    }
+
+    fn draw_ids_with(shader : &IdShader, state : &PipelineState) -> (Vec<f32>, DrawStats) {
+        let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgba32F);
+        let stats = rasterize(shader.0.len(), shader, state, &mut fb);
+        (fb.resolve_f32(0), stats)
+    }
+
+    fn draw_ids(shader : &IdShader, topology : PrimitiveTopology) -> Vec<f32> {
+        draw_ids_with(shader, &PipelineState { topology, ..Default::default() }).0
+    }
+
+    #[test]
+    fn varyings_follow_their_interpolation_qualifiers() {
+        // A strip of two triangles split along the diagonal x + y = 64, with varying w
+        let ws = [1., 2., 3., 1.5];
+        let corners = [(-1., -1.), (1., -1.), (-1., 1.), (1., 1.)];
+        let vs = corners.iter().zip(ws.iter()).map(|(c, w)| Vector4::new(c.0 * w, c.1 * w, 0., *w)).collect();
+        let out = draw_ids(&IdShader(vs), PrimitiveTopology::TriangleStrip);
+        for (i, p) in out.chunks(4).enumerate() {
+            // Rows of resolve_f32 are in image order, top to bottom
+            let (x, y) = ((i % 64) as f32 + 0.5, (63 - i / 64) as f32 + 0.5);
+            let id = if x + y < 64. {0.} else {1.};
+            assert_eq!(p[0], id, "pixel ({}, {})", x, y);
+            assert!((p[1] - x).abs() < 1e-3, "pixel ({}, {}) x {}", x, y, p[1]);
+        }
+
+        // The provoking vertex and the one after it lie beyond the near plane and are
+        // clipped away
+        let vs = vec![Vector4::new(-1., -1., 2., 1.), Vector4::new(1., -1., 2., 1.), Vector4::new(0., 1., 0., 1.)];
+        let out = draw_ids(&IdShader(vs), PrimitiveTopology::TriangleList);
+        let covered : Vec<_> = out.chunks(4).filter(|p| p[3] == 1.).collect();
+        assert!(!covered.is_empty());
+        assert!(covered.iter().all(|p| p[0] == 0.));
+    }
+
+    // A floor at y = -1 reaching from behind the eye to z = -50, far wider than the view.
+    // Clipping against the near plane alone leaves vertices a long way outside the
+    // window, the guard band planes bring them back in range
+    #[test]
+    fn large_triangles_crossing_the_near_plane_are_drawn() {
+        let proj = crate::transforms::perspective(-0.1, 0.1, -0.1, 0.1, -0.1, -100.);
+        let corners = [(-1e5, 1.), (1e5, 1.), (1e5, -50.), (-1e5, 1.), (1e5, -50.), (-1e5, -50.)];
+        let vs = corners.iter().map(|&(x, z)| proj * Vector4::new(x, -1., z, 1.)).collect();
+        let (out, stats) = draw_ids_with(&IdShader(vs), &PipelineState::default());
+        // Clipped and drawn rather than rejected
+        assert_eq!((stats.out_of_range, stats.non_finite), (0, 0));
+        assert!(stats.primitives_out >= 2);
+        assert_eq!(stats.fragments_generated, 31 * 64);
+        for (i, p) in out.chunks(4).enumerate() {
+            // The ray through the pixel centre meets the floor at z = 1 / y in normalized
+            // device coordinates, within the floor for rows below y = -1 / 50
+            let y = (63 - i / 64) as f32 + 0.5;
+            let covered = (y / 32. - 1.) < -0.02;
+            assert_eq!(p[3] == 1., covered, "pixel ({}, {})", i % 64, y);
+        }
+
+        // A line across the floor at z = -2 lands on y = -0.5, one pixel in every column
+        let vs = vec![proj * Vector4::new(-1e5, -1., -2., 1.), proj * Vector4::new(1e5, -1., -2., 1.)];
+        let out = draw_ids(&IdShader(vs), PrimitiveTopology::LineList);
+        let rows : Vec<_> = out.chunks(4).enumerate().filter(|(_, p)| p[3] == 1.).map(|(i, _)| 63 - i / 64).collect();
+        assert_eq!(rows.len(), 64);
+        assert!(rows.iter().all(|&r| r == 15 || r == 16), "rows {:?}", rows);
+    }
+
+    // A counter-clockwise triangle on the left half of the screen and a clockwise one
+    // on the right, with vertex ids 0 and 3 as their provoking vertices
+    #[test]
+    fn culling_follows_the_winding_of_front_faces() {
+        let vs = [(-1., -1.), (-0.2, -1.), (-1., 1.), (0.2, -1.), (0.2, 1.), (1., -1.)];
+        let shader = IdShader(vs.iter().map(|&(x, y)| Vector4::new(x, y, 0., 1.)).collect());
+        let cases = [
+            (CullMode::None, FrontFace::CounterClockwise, [true, true]),
+            (CullMode::None, FrontFace::Clockwise, [true, true]),
+            (CullMode::Back, FrontFace::CounterClockwise, [true, false]),
+            (CullMode::Back, FrontFace::Clockwise, [false, true]),
+            (CullMode::Front, FrontFace::CounterClockwise, [false, true]),
+            (CullMode::Front, FrontFace::Clockwise, [true, false])
+        ];
+        for &(cull_mode, front_face, drawn) in cases.iter() {
+            let state = PipelineState { cull_mode, front_face, ..Default::default() };
+            let (out, stats) = draw_ids_with(&shader, &state);
+            let culled = drawn.iter().filter(|&&d| !d).count() as u64;
+            assert_eq!((stats.culled, stats.primitives_out), (culled, 2 - culled), "{:?} {:?}", cull_mode, front_face);
+            // Pixels (4, 4) and (59, 4) lie inside the left and right triangle
+            for (&d, &(x, id)) in drawn.iter().zip([(4, 0.), (59, 3.)].iter()) {
+                let p = &out[((63 - 4) * 64 + x) * 4..][..4];
+                let expected = if d {[id, 1.]} else {[0., 0.]};
+                assert_eq!([p[0], p[3]], expected, "{:?} {:?} pixel ({}, 4)", cull_mode, front_face, x);
+            }
+        }
+    }
+
+    // The back end hands out tile rows in whatever order threads ask for them, but every
+    // pixel only ever sees its primitives in submission order
+    #[test]
+    fn output_does_not_depend_on_thread_count() {
+        let scene = random_scene(3, 200);
+        let state = PipelineState { blend : crate::state::BlendState::alpha(), threads : 1, ..Default::default() };
+        let single = render_scene(&scene, &state, SampleCount::X4);
+        let multi = render_scene(&scene, &PipelineState { threads : 7, ..state }, SampleCount::X4);
+        assert!(single.0 == multi.0 && single.1 == multi.1);
+
+        // Nothing to do for an empty framebuffer
+        let mut fb = Framebuffer::new(0, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgba32F);
+        assert_eq!(rasterize(scene.0.len(), &scene, &state, &mut fb).fragments_generated, 0);
+    }
+
+    // A square centred on the screen with a half size, depth and colour in normalized
+    // device coordinates, wound counter-clockwise or clockwise
+    fn colored_square(size : f32, z : f32, color : Vector4<f32>, ccw : bool) -> ColorShader {
+        let mut corners = [(-1., -1.), (1., -1.), (1., 1.), (-1., -1.), (1., 1.), (-1., 1.)];
+        if !ccw {
+            corners.swap(1, 2);
+            corners.swap(4, 5);
+        }
+        ColorShader(corners.iter().map(|&(x, y)| (Vector4::new(x * size, y * size, z, 1.), color)).collect())
+    }
+
+    #[test]
+    fn stencil_operations_follow_the_tests() {
+        use crate::state::{StencilFace, StencilOp, StencilState};
+        let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgba32F).with_stencil();
+        let (red, green) = (Vector4::new(1., 0., 0., 1.), Vector4::new(0., 1., 0., 1.));
+        let mut draw = |shader : &ColorShader, stencil : StencilState| {
+            let state = PipelineState { stencil, ..Default::default() };
+            rasterize(shader.0.len(), shader, &state, &mut fb);
+            // Pixels (32, 32) inside the marked square and (4, 4) outside it
+            let stencil = fb.stencil.as_ref().unwrap();
+            let colors = fb.resolve_f32(0);
+            let color = |x : usize, y : usize| Vector4::from_column_slice(&colors[((63 - y) * 64 + x) * 4..][..4]);
+            ((stencil[32 * 64 + 32], stencil[4 * 64 + 4]), (color(32, 32), color(4, 4)))
+        };
+        let face = |compare, fail, depth_fail, pass| StencilFace { compare, fail, depth_fail, pass };
+        let keep = StencilOp::Keep;
+        let enabled = StencilState { enabled : true, ..Default::default() };
+
+        // Mark the middle of the screen with 1
+        let mark = StencilState { reference : 1, front : face(CompareFunc::Always, keep, keep, StencilOp::Replace), ..enabled };
+        assert_eq!(draw(&colored_square(0.5, 0., red, true), mark).0, (1, 0));
+
+        // Only the marked pixels are drawn, and incremented
+        let inside = StencilState { reference : 1, front : face(CompareFunc::Equal, keep, keep, StencilOp::IncrClamp), ..enabled };
+        let zeros = Vector4::zeros();
+        assert_eq!(draw(&colored_square(1., 0.5, green, true), inside), ((2, 0), (green, zeros)));
+
+        // The test only sees the low bits of 0x12, and only the high bits of the new
+        // value are written, both for passing and failing samples
+        let masked = StencilState {
+            reference : 0x12,
+            read_mask : 0x0f,
+            write_mask : 0xf0,
+            front : face(CompareFunc::Equal, StencilOp::Invert, keep, StencilOp::Replace),
+            ..enabled
+        };
+        assert_eq!(draw(&colored_square(1., 0.6, red, true), masked).0, (0x12, 0xf0));
+
+        // Behind everything drawn so far, only the outside where no depth was written
+        // passes the depth test
+        let depth_fail = StencilState { front : face(CompareFunc::Always, keep, StencilOp::IncrWrap, StencilOp::Zero), ..enabled };
+        assert_eq!(draw(&colored_square(1., -0.9, red, true), depth_fail).0, (0x13, 0));
+
+        // Clockwise triangles are back facing and use the back face state
+        let back = StencilState {
+            front : face(CompareFunc::Never, keep, keep, keep),
+            back : face(CompareFunc::Always, keep, keep, StencilOp::DecrClamp),
+            ..enabled
+        };
+        assert_eq!(draw(&colored_square(1., 0.9, green, false), back), ((0x12, 0), (green, green)));
+    }
+
+    // The screen maps onto an 8x8 viewport at x = 8 with depth from 0.2 to 0.4, and the
+    // scissor, reaching past the end of u32, cuts it down to 10..16 by 0..4
+    #[test]
+    fn viewport_and_scissor_place_fragments() {
+        use crate::state::{Scissor, Viewport};
+        let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgb8);
+        let state = PipelineState {
+            viewport : Some(Viewport { x : 8., y : 0., width : 8., height : 8., min_depth : 0.2, max_depth : 0.4 }),
+            scissor : Some(Scissor { x : 10, y : 0, width : u32::MAX, height : 4 }),
+            ..Default::default()
+        };
+        let stats = rasterize(6, &SquareShader(vec![(1., 0.)]), &state, &mut fb);
+        assert_eq!(stats.fragments_generated, 24);
+        for (i, z) in fb.depth().iter().enumerate() {
+            let (x, y) = (i % 64, i / 64);
+            let expected = if (10..16).contains(&x) && y < 4 {0.3} else {f32::MIN};
+            assert!((z - expected).abs() < 1e-6, "pixel ({}, {}) depth {}", x, y, z);
+        }
+    }
+
+    // Varies as 0.5 x + 0.25 y in window coordinates, whatever the w of the vertices.
+    // Outputs both derivatives and how many lanes of the quad lie outside the triangle
+    struct DerivativeShader(Vec<Vector4<f32>>);
+
+    impl Shader for DerivativeShader {
+        type Varyings = NoPerspective<f32>;
+
+        fn vertex(&self, i : u32) -> (Vector4<f32>, NoPerspective<f32>) {
+            let p = self.0[i as usize];
+            let (x, y) = ((p.x / p.w + 1.) * 32., (p.y / p.w + 1.) * 32.);
+            (p, NoPerspective(0.5 * x + 0.25 * y))
+        }
+
+        fn fragment(&self, _v : &NoPerspective<f32>, input : &FragmentInput<NoPerspective<f32>>) -> FragmentOutput {
+            let quad = &input.quad;
+            let helpers = quad.bcs.iter().filter(|b| b.0 < 0. || b.1 < 0. || b.2 < 0.).count();
+            FragmentOutput::rgba(Vector4::new(quad.dfdx(|v| v.0), quad.dfdy(|v| v.0), helpers as f32, 1.))
+        }
+    }
+
+    #[test]
+    fn derivatives_of_affine_varyings_are_their_per_pixel_step() {
+        let vs = [(3., 5., 1.), (60., 11., 2.), (20., 58., 3.)];
+        let shader = DerivativeShader(vs.iter().map(|&(x, y, w)| Vector4::new((x / 32. - 1.) * w, (y / 32. - 1.) * w, 0., w)).collect());
+        let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgba32F);
+        rasterize(3, &shader, &PipelineState::default(), &mut fb);
+        let out = fb.resolve_f32(0);
+        let covered : Vec<_> = out.chunks(4).filter(|p| p[3] == 1.).collect();
+        assert!(covered.len() > 100);
+        // Quads along the edges have helper lanes
+        assert!(covered.iter().any(|p| p[2] > 0.));
+        for p in covered {
+            assert!((p[0] - 0.5).abs() < 1e-3 && (p[1] - 0.25).abs() < 1e-3, "derivatives {:?} with {} helpers", &p[..2], p[2]);
+        }
+    }
+
+    // Triangles given in window coordinates of a 64x64 framebuffer, outputs the
+    // coverage of every fragment
+    struct CoverageShader(Vec<(f32, f32)>);
+
+    impl Shader for CoverageShader {
+        type Varyings = ();
+
+        fn vertex(&self, i : u32) -> (Vector4<f32>, ()) {
+            let (x, y) = self.0[i as usize];
+            (Vector4::new(x / 32. - 1., y / 32. - 1., 0., 1.), ())
+        }
+
+        fn fragment(&self, _v : &(), input : &FragmentInput<()>) -> FragmentOutput {
+            FragmentOutput::rgba(Vector4::new(input.coverage, 0., 0., 1.))
+        }
+    }
+
+    // A sliver a quarter pixel high along row 10, between the pixel centres of x = 2.25
+    // to 12.75, widening linearly to the right
+    #[test]
+    fn conservative_triangles_cover_every_pixel_they_touch() {
+        let shader = CoverageShader(vec![(2.25, 10.125), (12.75, 10.125), (12.75, 10.375)]);
+        let draw = |conservative| {
+            let mut fb = Framebuffer::new(64, 64, SampleCount::X4).with_color(crate::framebuffer::Format::Rgba32F);
+            let stats = rasterize(3, &shader, &PipelineState { conservative, ..Default::default() }, &mut fb);
+            (stats.fragments_generated, fb.resolve_f32(0))
+        };
+        assert_eq!(draw(false).0, 0);
+
+        let (generated, out) = draw(true);
+        assert_eq!(generated, 11);
+        // The height of the sliver at x is (x - 2.25) / 42, integrated over the part of
+        // each pixel the sliver spans
+        let area = |a : f32, b : f32| ((b - 2.25).powi(2) - (a - 2.25).powi(2)) / 84.;
+        for x in 0..64 {
+            let p = &out[((63 - 10) * 64 + x) * 4..][..4];
+            let expected = if (2..13).contains(&x) {area((x as f32).max(2.25), (x as f32 + 1.).min(12.75))} else {0.};
+            assert!((p[0] - expected).abs() < 1e-5, "pixel ({}, 10) coverage {} expected {}", x, p[0], expected);
+        }
+    }
+
+    // Skipping blocks that hierarchical depth finds hidden must never change the result
+    #[test]
+    fn hierarchical_depth_does_not_change_the_output() {
+        // Two coplanar full screen squares first, so ties decide whether the second is drawn
+        let mut scene = colored_square(1., 0.5, Vector4::new(1., 0., 0., 1.), true);
+        scene.0.extend(colored_square(1., 0.5, Vector4::new(0., 1., 0., 1.), true).0);
+        scene.0.extend(random_scene(11, 200).0);
+        let base = PipelineState::default();
+        let with = |compare, clear_value| PipelineState { depth : DepthState { compare, clear_value, ..base.depth }, ..base };
+        let mut cases = vec![
+            (with(CompareFunc::Greater, f32::MIN), SampleCount::X1),
+            (with(CompareFunc::GreaterEqual, f32::MIN), SampleCount::X1),
+            (with(CompareFunc::Less, f32::MAX), SampleCount::X1),
+            (with(CompareFunc::LessEqual, f32::MAX), SampleCount::X1),
+            (PipelineState { depth_bias : DepthBias { constant : 4., slope : 1.5, clamp : 0. }, ..base }, SampleCount::X4),
+            (PipelineState { conservative : true, ..base }, SampleCount::X4)
+        ];
+        for &topology in [PrimitiveTopology::TriangleStrip, PrimitiveTopology::TriangleFan, PrimitiveTopology::LineList, PrimitiveTopology::LineStrip, PrimitiveTopology::PointList].iter() {
+            cases.push((PipelineState { topology, ..base }, SampleCount::X4));
+        }
+        for (state, samples) in cases {
+            let on = render_scene(&scene, &state, samples);
+            let off = render_scene(&scene, &PipelineState { depth : DepthState { hierarchical : false, ..state.depth }, ..state }, samples);
+            assert!(on.0 == off.0 && on.1 == off.1, "{:?}", state);
+        }
+    }
+
+    // Both triangles of a square hidden behind an earlier one are skipped in every 8x8
+    // block
+    #[test]
+    fn hierarchical_depth_counts_skipped_blocks() {
+        for &hierarchical in [true, false].iter() {
+            let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgb8);
+            let state = PipelineState { depth : DepthState { hierarchical, ..Default::default() }, ..Default::default() };
+            rasterize(6, &SquareShader(vec![(1., 0.5)]), &state, &mut fb);
+            let stats = rasterize(6, &SquareShader(vec![(1., 0.)]), &state, &mut fb);
+            let expected = if hierarchical {(0, 2 * 64)} else {(64 * 64, 0)};
+            assert_eq!((stats.fragments_generated, stats.hiz_culled), expected);
+            assert_eq!(stats.samples_passed, 0);
+        }
+    }
+
+    #[test]
+    fn depth_bias_moves_fragments_towards_the_viewer() {
+        // A coplanar second draw only passes Greater with a bias
+        for &(constant, passed) in [(0., 0), (1., 64 * 64)].iter() {
+            let mut fb = Framebuffer::new(64, 64, SampleCount::X1).with_color(crate::framebuffer::Format::Rgb8);
+            let state = PipelineState::default();
+            rasterize(6, &SquareShader(vec![(1., 0.)]), &state, &mut fb);
+            let state = PipelineState { depth_bias : DepthBias { constant, ..Default::default() }, ..state };
+            assert_eq!(rasterize(6, &SquareShader(vec![(1., 0.)]), &state, &mut fb).samples_passed, passed);
+        }
+
+        // A square whose window depth grows by 1/128 per pixel in x. The slope term adds
+        // twice that, unless clamped
+        let mut square = colored_square(1., 0., Vector4::new(1., 1., 1., 1.), true);
+        for v in square.0.iter_mut() {
+            v.0.z = 0.5 * v.0.x;
+        }
+        let depth = |depth_bias| render_scene(&square, &PipelineState { depth_bias, ..Default::default() }, SampleCount::X1).1;
+        let plain = depth(DepthBias::default());
+        let sloped = depth(DepthBias { slope : 2., ..Default::default() });
+        let clamped = depth(DepthBias { slope : 2., clamp : 0.01, ..Default::default() });
+        for i in 0..plain.len() {
+            assert!((sloped[i] - plain[i] - 1. / 64.).abs() < 1e-6, "sample {} offset {}", i, sloped[i] - plain[i]);
+            assert!((clamped[i] - plain[i] - 0.01).abs() < 1e-6, "sample {} offset {}", i, clamped[i] - plain[i]);
+        }
+    }
+}
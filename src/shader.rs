@@ -1,45 +1,102 @@
-use nalgebra::{Vector3, Vector4, Matrix3, Matrix4};
+use std::ops::Sub;
+use nalgebra::{Vector2, Vector3, Vector4, Matrix3, Matrix4};
 use image::{Rgb, RgbImage};
+use super::framebuffer::MAX_COLOR_ATTACHMENTS;
+use super::shadow::ShadowMap;
+use super::varyings::{Barycentrics, Flat, Varyings};
 
-macro_rules! unwrap_vertex_attr_1f {
-    ($v : expr, $t : ident, $c : ident, $msg : expr) => {
-        match $v {
-            $t::$c(val) => val,
-            _ => panic!($msg)
-        };
-    };
+// Output of the fragment stage. colors[i] is written to colour attachment i as RGBA,
+// in 0..1 for 8-bit formats. Setting drop discards the fragment, depth replaces the
+// interpolated depth and is only honoured for shaders that use late-Z
+pub struct FragmentOutput {
+    pub colors : [Vector4<f32>; MAX_COLOR_ATTACHMENTS],
+    pub depth : Option<f32>,
+    pub drop : bool
 }
 
-macro_rules! unwrap_vertex_attr_2f {
-    ($v : expr, $t : ident, $c : ident, $msg : expr) => {
-        match $v {
-            $t::$c(v1, v2) => (v1, v2),
-            _ => panic!($msg)
-        };
-    };
+impl FragmentOutput {
+    // Opaque 8-bit colour
+    pub fn color(color : Rgb<u8>) -> FragmentOutput {
+        let c = Vector4::new(color[0] as f32, color[1] as f32, color[2] as f32, 255.) / 255.;
+        FragmentOutput::rgba(c)
+    }
+
+    pub fn rgba(color : Vector4<f32>) -> FragmentOutput {
+        let mut colors = [Vector4::zeros(); MAX_COLOR_ATTACHMENTS];
+        colors[0] = color;
+        FragmentOutput { colors, depth : None, drop : false }
+    }
+
+    // Set the colour written to another attachment
+    pub fn with_target(mut self, index : usize, color : Vector4<f32>) -> FragmentOutput {
+        self.colors[index] = color;
+        self
+    }
 }
 
-// Vertex attributes
-pub enum VertexAttr {
-    TextureCoord(f32, f32),
-    LightIntensity(f32)
+// Fragments are shaded in 2x2 pixel quads. bcs holds the screen space barycentrics at
+// the pixel centres of the quad, ordered bottom left, bottom right, top left, top
+// right, and lane is the pixel being shaded. Lanes outside the primitive are helpers
+// whose barycentrics extend the plane of the primitive, so derivatives stay defined
+// along its edges. Varyings of the other lanes are only interpolated when asked for
+pub struct Quad<'a, V> {
+    pub bcs : [(f32, f32, f32); 4],
+    pub lane : usize,
+    ws : (f32, f32, f32),
+    vs : [&'a V; 3]
 }
 
-pub trait Shader {
-    fn vertex(&self, t : u32, v : u32) -> (Vector4<f32>, Vec<VertexAttr>);
-    fn fragment(&self, bc: (f32, f32, f32), ws : (f32, f32, f32),  attrs : (&Vec<VertexAttr>, &Vec<VertexAttr>, &Vec<VertexAttr>)) -> (Rgb<u8>, bool);
+impl<'a, V : Varyings> Quad<'a, V> {
+    pub(crate) fn new(bcs : [(f32, f32, f32); 4], lane : usize, ws : (f32, f32, f32), vs : [&'a V; 3]) -> Quad<'a, V> {
+        Quad { bcs, lane, ws, vs }
+    }
+
+    // Varyings at the centre of a lane
+    pub fn varyings(&self, lane : usize) -> V {
+        V::interpolate(self.vs, &Barycentrics::new(self.bcs[lane], self.ws))
+    }
+
+    // Screen-space derivatives of any value computed from the varyings, like dFdx and
+    // dFdy. f is evaluated at the lanes on the same row or column as the shaded pixel
+    pub fn dfdx<T : Sub<Output = T>, F : Fn(&V) -> T>(&self, f : F) -> T {
+        let row = self.lane & 2;
+        f(&self.varyings(row + 1)) - f(&self.varyings(row))
+    }
+
+    pub fn dfdy<T : Sub<Output = T>, F : Fn(&V) -> T>(&self, f : F) -> T {
+        let col = self.lane & 1;
+        f(&self.varyings(col + 2)) - f(&self.varyings(col))
+    }
 }
 
-fn interpolate_tex(bc : (f32, f32, f32), ws : (f32, f32, f32) , uvs : ((f32, f32), (f32, f32), (f32, f32)), w_reci : f32) -> (f32, f32) {
-    let u_s = bc.0 * (uvs.0).0 * ws.0 + bc.1 * (uvs.1).0 * ws.1 + bc.2 * (uvs.2).0 * ws.2;
-    let v_s = bc.0 * (uvs.0).1 * ws.0 + bc.1 * (uvs.1).1 * ws.1 + bc.2 * (uvs.2).1 * ws.2;
-    let u = u_s / w_reci;
-    let v = v_s / w_reci;
-    (u, v)
+// What the fragment stage knows about a fragment besides its varyings
+pub struct FragmentInput<'a, V> {
+    pub quad : Quad<'a, V>,
+    // Samples of the pixel generated by the primitive
+    pub sample_mask : u32,
+    // Fraction of the pixel area covered by the primitive. Exact for conservatively
+    // rasterized triangles, otherwise the fraction of samples covered
+    pub coverage : f32
 }
 
-fn interpolate_one_attr(bc : (f32, f32, f32), ws : (f32, f32, f32), attrs : (f32, f32, f32), w_reci : f32) -> f32 {
-    (bc.0 * attrs.0 * ws.0 + bc.1 * attrs.1 * ws.1 + bc.2 * attrs.2 * ws.2) / w_reci
+// The vertex stage is called with the index of a vertex, already looked up in the
+// index buffer for indexed draws, and returns its clip-space position and varyings, the
+// pipeline clips and divides by w. The fragment stage gets the varyings interpolated
+// at the fragment. Lines and points are shaded as triangles whose unused vertices
+// repeat the last one, and the first vertex of every primitive provokes flat varyings.
+// Both stages are invoked from several threads at once
+pub trait Shader : Sync {
+    type Varyings : Varyings;
+
+    fn vertex(&self, i : u32) -> (Vector4<f32>, Self::Varyings);
+    fn fragment(&self, v : &Self::Varyings, input : &FragmentInput<Self::Varyings>) -> FragmentOutput;
+
+    // By default the depth test runs before the fragment stage so hidden fragments are
+    // never shaded. Shaders that write depth, or want discarded fragments to be tested
+    // after shading, return true to move the test after the fragment stage
+    fn late_z(&self) -> bool {
+        false
+    }
 }
 
 fn clamp(r : f32, g : f32, b : f32) -> Rgb<u8> {
@@ -66,10 +123,40 @@ fn calc_blinnphong_color(diffuse_int : f32, spec_int : f32, ambient : f32, diffu
     clamp(r, g, b)
 }
 
+// Nearest texel at texture coordinates tc, v points up the image
+fn texel<'a>(img : &'a RgbImage, tc : &Vector2<f32>) -> &'a Rgb<u8> {
+    let tx = (tc.x * (img.width() - 1) as f32) as u32;
+    let ty = img.height() - (f32::round(tc.y * (img.height() - 1) as f32) as u32) - 1;
+    img.get_pixel(tx, ty)
+}
+
+// Diffuse and specular intensity of the lights at a point with unit normal n, seen
+// from the unit direction e. The first light is scaled by its shadow visibility
+fn blinn_phong_intensity(n : &Vector3<f32>, e : &Vector3<f32>, light_source : &[(Vector3<f32>, f32)], phong_exp : f32, visibility : f32) -> (f32, f32) {
+    let mut diffuse_int = 0.;
+    let mut spec_int = 0.;
+    for (i, (li_dir, intensity)) in light_source.iter().enumerate() {
+        let intensity = if i == 0 {intensity * visibility} else {*intensity};
+        let l = li_dir.normalize();
+        let n_dot_l = n.dot(&l);
+        // No highlight on the side facing away from the light
+        if n_dot_l > 0. {
+            let h = (e - l).normalize();
+            diffuse_int += n_dot_l * intensity;
+            spec_int += h.dot(n).abs().powf(phong_exp) * intensity;
+        }
+    }
+    (diffuse_int, spec_int)
+}
+
+// Fraction of the first light reaching a world space point
+fn shadow_visibility(shadow : Option<&ShadowMap>, position : &Vector3<f32>) -> f32 {
+    shadow.map_or(1., |map| map.visibility(position))
+}
+
 // Most basic shader, only has ambient lighting
 pub struct VanillaShader<'a> {
     pub m : Matrix4<f32>,
-    pub indices :  &'a Vec<u32>,
     pub positions : &'a Vec<f32>,
     pub texcoords : &'a Vec<f32>,
     pub diffuse_height : u32,
@@ -78,48 +165,59 @@ pub struct VanillaShader<'a> {
 }
 
 impl Shader for VanillaShader<'_> {
+    // Texture coordinates
+    type Varyings = Vector2<f32>;
 
-    fn vertex(&self, t : u32, v: u32) -> (Vector4<f32>, Vec<VertexAttr>) {
-        let id = self.indices[(t * 3 + v) as usize] as usize;
+    fn vertex(&self, i : u32) -> (Vector4<f32>, Vector2<f32>) {
+        let id = i as usize;
         let vert = Vector4::new(self.positions[id*3], self.positions[id*3+1], self.positions[id*3+2], 1.);
-        let tc = VertexAttr::TextureCoord(self.texcoords[id*2], self.texcoords[id*2 + 1]);
-        let v = self.m * vert;
-        let v = Vector4::new(v.x / v.w, v.y / v.w, v.z / v.w, 1. / v.w);
-        (v, vec!(tc))
-    }
-
-    fn fragment(&self, bc: (f32, f32, f32), ws : (f32, f32, f32), attrs : (&Vec<VertexAttr>, &Vec<VertexAttr>, &Vec<VertexAttr>)) -> (Rgb<u8>, bool) {
-        let uv0 = unwrap_vertex_attr_2f!(attrs.0[0], VertexAttr, TextureCoord, "Expecting TextureCoord!");
-        let uv1 = unwrap_vertex_attr_2f!(attrs.1[0], VertexAttr, TextureCoord, "Expecting TextureCoord!");
-        let uv2 = unwrap_vertex_attr_2f!(attrs.2[0], VertexAttr, TextureCoord, "Expecting TextureCoord!");
-        let w_reci = bc.0 * ws.0 + bc.1 * ws.1 + bc.2 * ws.2;
-        let (u, v) = interpolate_tex(bc, ws, (uv0, uv1, uv2), w_reci);
+        let tc = Vector2::new(self.texcoords[id*2], self.texcoords[id*2 + 1]);
+        (self.m * vert, tc)
+    }
+
+    fn fragment(&self, tc : &Vector2<f32>, _input : &FragmentInput<Vector2<f32>>) -> FragmentOutput {
+        let (u, v) = (tc.x, tc.y);
         let tx = (u * (self.diffuse_width - 1) as f32) as u32;
         let ty = self.diffuse_height - (f32::round(v * (self.diffuse_height - 1) as f32) as u32) - 1;
         let color = self.diffuse.get_pixel(tx, ty);
-        (Rgb([color[0],color[1],color[2]]), false)
+        FragmentOutput::color(Rgb([color[0],color[1],color[2]]))
     }
 
 }
 
+// Texture coordinates and light intensity computed per vertex. The first light is kept
+// apart from the others in light_intensity, so the fragment shader can scale it by its
+// shadow at the world space position
+#[derive(Clone, Copy)]
+pub struct GouraudVaryings {
+    pub tc : Vector2<f32>,
+    pub light_intensity : f32,
+    pub first_light : f32,
+    pub position : Vector3<f32>
+}
+
+crate::varyings!(GouraudVaryings { tc, light_intensity, first_light, position });
 
-// An implementation of GauraudShader
+// An implementation of GauraudShader, world takes positions to world space
 pub struct GouraudShader<'a> {
     pub mvp : Matrix4<f32>,
+    pub world : Matrix4<f32>,
     pub model : Matrix3<f32>,
-    pub indices : &'a Vec<u32>,
     pub positions : &'a Vec<f32>,
     pub texcoords : &'a Vec<f32>,
     pub normals : &'a Vec<f32>,
     pub diffuse : &'a RgbImage,
     pub light_source : &'a Vec<(Vector3<f32>, f32)>,
+    // Shadows cast from the first light, if any
+    pub shadow : Option<&'a ShadowMap>,
     pub ambient : f32
 }
 
 impl Shader for GouraudShader<'_> {
+    type Varyings = GouraudVaryings;
 
-    fn vertex(&self, t : u32, v: u32) -> (Vector4<f32>, Vec<VertexAttr>) {
-        let idx = self.indices[(t * 3 + v) as usize] as usize;
+    fn vertex(&self, i : u32) -> (Vector4<f32>, GouraudVaryings) {
+        let idx = i as usize;
         // calculate normal vector
         // model in left hand coord, flip x y z val
         let n = Vector3::new(-self.normals[idx*3], -self.normals[idx*3+1], -self.normals[idx*3+2]);
@@ -127,100 +225,157 @@ impl Shader for GouraudShader<'_> {
         let v = Vector4::new(self.positions[idx*3], self.positions[idx*3+1], self.positions[idx*3+2], 1.);
         // calculate light intensity
         let mut vert_intensity = 0.;
-        for (li_dir, li_intensity) in self.light_source.iter() {
-            vert_intensity += n.dot(&li_dir.normalize()).max(0.) * li_intensity;
+        let mut first_light = 0.;
+        for (i, (li_dir, li_intensity)) in self.light_source.iter().enumerate() {
+            let intensity = n.dot(&li_dir.normalize()).max(0.) * li_intensity;
+            if i == 0 {
+                first_light = intensity;
+            } else {
+                vert_intensity += intensity;
+            }
         }
-        let vert_intensity = VertexAttr::LightIntensity(vert_intensity);
-        let tc = VertexAttr::TextureCoord(self.texcoords[idx*2], self.texcoords[idx*2 + 1]);
-        let v = self.mvp * v;
-        let v = Vector4::new(v.x / v.w, v.y / v.w, v.z / v.w, 1. / v.w);
-        (v, vec!(tc, vert_intensity))
-    }
-
-    fn fragment(&self, bc: (f32, f32, f32), ws : (f32, f32, f32), attrs : (&Vec<VertexAttr>, &Vec<VertexAttr>, &Vec<VertexAttr>)) -> (Rgb<u8>, bool) {
-        let msg_texcoord = "Expecting TextureCoord!";
-        let msg_li_int = "Expecting LightIntensity!";
-        let uv0 = unwrap_vertex_attr_2f!(attrs.0[0], VertexAttr, TextureCoord, msg_texcoord);
-        let uv1 = unwrap_vertex_attr_2f!(attrs.1[0], VertexAttr, TextureCoord, msg_texcoord);
-        let uv2 = unwrap_vertex_attr_2f!(attrs.2[0], VertexAttr, TextureCoord, msg_texcoord);
-        let li_int_v0 = unwrap_vertex_attr_1f!(attrs.0[1], VertexAttr, LightIntensity, msg_li_int);
-        let li_int_v1 = unwrap_vertex_attr_1f!(attrs.1[1], VertexAttr, LightIntensity, msg_li_int);
-        let li_int_v2 = unwrap_vertex_attr_1f!(attrs.2[1], VertexAttr, LightIntensity, msg_li_int);
-        let w_reci = bc.0 * ws.0 + bc.1 * ws.1 + bc.2 * ws.2;
-        let (u, v) = interpolate_tex(bc, ws, (uv0, uv1, uv2), w_reci);
-        let li_int = interpolate_one_attr(bc, ws, (li_int_v0, li_int_v1, li_int_v2), w_reci);
+        let tc = Vector2::new(self.texcoords[idx*2], self.texcoords[idx*2 + 1]);
+        let position = (self.world * v).xyz();
+        (self.mvp * v, GouraudVaryings { tc, light_intensity : vert_intensity, first_light, position })
+    }
+
+    fn fragment(&self, vary : &GouraudVaryings, _input : &FragmentInput<GouraudVaryings>) -> FragmentOutput {
+        let (u, v) = (vary.tc.x, vary.tc.y);
+        let li_int = vary.light_intensity + vary.first_light * shadow_visibility(self.shadow, &vary.position);
         let tx = (u * (self.diffuse.width() - 1) as f32) as u32;
         let ty = self.diffuse.height()- (f32::round(v * (self.diffuse.height() - 1) as f32) as u32) - 1;
         let diffuse_color = self.diffuse.get_pixel(tx, ty);
-        (calc_gouraud_color(li_int, self.ambient, diffuse_color), false)
+        FragmentOutput::color(calc_gouraud_color(li_int, self.ambient, diffuse_color))
     }
 
 }
 
+// Texture coordinates, and normal and position in world space
+#[derive(Clone, Copy)]
+pub struct BlinnPhongVaryings {
+    pub tc : Vector2<f32>,
+    pub normal : Vector3<f32>,
+    pub position : Vector3<f32>
+}
+
+crate::varyings!(BlinnPhongVaryings { tc, normal, position });
+
+// Blinn-Phong lighting evaluated per pixel from the interpolated normal, with the view
+// vector pointing from each pixel towards the eye. world takes positions to world
+// space and model takes normals there
 pub struct BlinnPhongShader<'a> {
     pub mvp : Matrix4<f32>,
+    pub world : Matrix4<f32>,
     pub model : Matrix3<f32>,
-    pub e : Vector3<f32>,
-    pub indices : &'a Vec<u32>,
+    pub eye : Vector3<f32>,
     pub positions : &'a Vec<f32>,
     pub texcoords : &'a Vec<f32>,
     pub normals : &'a Vec<f32>,
     pub diffuse : &'a RgbImage,
     pub spec : &'a RgbImage,
     pub light_source : &'a Vec<(Vector3<f32>, f32)>,
+    // Shadows cast from the first light, if any
+    pub shadow : Option<&'a ShadowMap>,
     pub ambient : f32,
     pub phong_exp : f32
 }
 
-// A Blinn-Phong Shader
 impl Shader for BlinnPhongShader<'_> {
+    type Varyings = BlinnPhongVaryings;
 
-    fn vertex(&self, t : u32, v: u32) -> (Vector4<f32>, Vec<VertexAttr>) {
-        let idx = self.indices[(t * 3 + v) as usize] as usize;
-        // calculate normal vector
+    fn vertex(&self, i : u32) -> (Vector4<f32>, BlinnPhongVaryings) {
+        let idx = i as usize;
         // model in left hand coord, flip x y z val
         let n = Vector3::new(-self.normals[idx*3], -self.normals[idx*3+1], -self.normals[idx*3+2]);
-        let n = (self.model * n).normalize();
         let v = Vector4::new(self.positions[idx*3], self.positions[idx*3+1], self.positions[idx*3+2], 1.);
-        // calculate diffuse and specular light intensity
-        let mut diffuse_intensity = 0.;
-        let mut spec_intensity = 0.;
-        for (li_dir, intensity) in self.light_source.iter() {
-            let l = li_dir;
-            let h = (self.e + -l).normalize();
-            diffuse_intensity += n.dot(&l).max(0.) * intensity;
-            spec_intensity += h.dot(&n).abs().powf(self.phong_exp) * intensity;
-        }
-        //println!("{}", spec_intensity);
-        let diffuse_intensity = VertexAttr::LightIntensity(diffuse_intensity);
-        let spec_intensity = VertexAttr::LightIntensity(spec_intensity);
-        let tc = VertexAttr::TextureCoord(self.texcoords[idx*2], self.texcoords[idx*2 + 1]);
-        let v = self.mvp * v;
-        let v = Vector4::new(v.x / v.w, v.y / v.w, v.z / v.w, 1. / v.w);
-        (v, vec!(tc, diffuse_intensity, spec_intensity))
-    }
-
-    fn fragment(&self, bc: (f32, f32, f32), ws : (f32, f32, f32), attrs : (&Vec<VertexAttr>, &Vec<VertexAttr>, &Vec<VertexAttr>)) -> (Rgb<u8>, bool) {
-        let msg_texcoord = "Expecting TextureCoord!";
-        let msg_li_int = "Expecting LightIntensity!";
-        let uv0 = unwrap_vertex_attr_2f!(attrs.0[0], VertexAttr, TextureCoord, msg_texcoord);
-        let uv1 = unwrap_vertex_attr_2f!(attrs.1[0], VertexAttr, TextureCoord, msg_texcoord);
-        let uv2 = unwrap_vertex_attr_2f!(attrs.2[0], VertexAttr, TextureCoord, msg_texcoord);
-        let diffuse_int_v0 = unwrap_vertex_attr_1f!(attrs.0[1], VertexAttr, LightIntensity, msg_li_int);
-        let diffuse_int_v1 = unwrap_vertex_attr_1f!(attrs.1[1], VertexAttr, LightIntensity, msg_li_int);
-        let diffuse_int_v2 = unwrap_vertex_attr_1f!(attrs.2[1], VertexAttr, LightIntensity, msg_li_int);
-        let spec_int_v0 = unwrap_vertex_attr_1f!(attrs.0[2], VertexAttr, LightIntensity, msg_li_int);
-        let spec_int_v1 = unwrap_vertex_attr_1f!(attrs.1[2], VertexAttr, LightIntensity, msg_li_int);
-        let spec_int_v2 = unwrap_vertex_attr_1f!(attrs.2[2], VertexAttr, LightIntensity, msg_li_int);
-        let w_reci = bc.0 * ws.0 + bc.1 * ws.1 + bc.2 * ws.2;
-        let (u, v) = interpolate_tex(bc, ws, (uv0, uv1, uv2), w_reci);
-        let diffuse_int = interpolate_one_attr(bc, ws, (diffuse_int_v0, diffuse_int_v1, diffuse_int_v2), w_reci);
-        let spec_int = interpolate_one_attr(bc, ws, (spec_int_v0, spec_int_v1, spec_int_v2), w_reci);
-        let tx = (u * (self.diffuse.width() - 1) as f32) as u32;
-        let ty = self.diffuse.height() - (f32::round(v * (self.diffuse.height() - 1) as f32) as u32) - 1;
-        let diffuse_color = self.diffuse.get_pixel(tx, ty);
-        let spec_color = self.spec.get_pixel(tx, ty);
-        (calc_blinnphong_color(diffuse_int, spec_int, self.ambient, diffuse_color, spec_color), false)
+        let tc = Vector2::new(self.texcoords[idx*2], self.texcoords[idx*2 + 1]);
+        let position = (self.world * v).xyz();
+        (self.mvp * v, BlinnPhongVaryings { tc, normal : self.model * n, position })
     }
 
-}
\ No newline at end of file
+    fn fragment(&self, vary : &BlinnPhongVaryings, _input : &FragmentInput<BlinnPhongVaryings>) -> FragmentOutput {
+        // Interpolated normals are shorter than unit length between vertices
+        let n = vary.normal.normalize();
+        let e = (self.eye - vary.position).normalize();
+        let visibility = shadow_visibility(self.shadow, &vary.position);
+        let (diffuse_int, spec_int) = blinn_phong_intensity(&n, &e, self.light_source, self.phong_exp, visibility);
+        let diffuse_color = texel(self.diffuse, &vary.tc);
+        let spec_color = texel(self.spec, &vary.tc);
+        FragmentOutput::color(calc_blinnphong_color(diffuse_int, spec_int, self.ambient, diffuse_color, spec_color))
+    }
+
+}
+
+// Texture coordinates, tangent frame and position in world space. The normal and
+// tangent are not flipped to the left handed model yet, so the bitangent sign of the
+// generated tangents still applies
+#[derive(Clone, Copy)]
+pub struct NormalMapVaryings {
+    pub tc : Vector2<f32>,
+    pub normal : Vector3<f32>,
+    pub tangent : Vector3<f32>,
+    pub sign : Flat<f32>,
+    pub position : Vector3<f32>
+}
+
+crate::varyings!(NormalMapVaryings { tc, normal, tangent, sign, position });
+
+// Per pixel Blinn-Phong with normals read from a tangent space normal map. tangents
+// holds four floats per vertex as made by tangents::generate, the tangent and the sign
+// of the bitangent. Like MikkTSpace the bitangent is rebuilt per pixel from the
+// interpolated normal and tangent
+pub struct NormalMapShader<'a> {
+    pub mvp : Matrix4<f32>,
+    pub world : Matrix4<f32>,
+    pub model : Matrix3<f32>,
+    pub eye : Vector3<f32>,
+    pub positions : &'a Vec<f32>,
+    pub texcoords : &'a Vec<f32>,
+    pub normals : &'a Vec<f32>,
+    pub tangents : &'a Vec<f32>,
+    pub diffuse : &'a RgbImage,
+    pub spec : &'a RgbImage,
+    pub normal_map : &'a RgbImage,
+    pub light_source : &'a Vec<(Vector3<f32>, f32)>,
+    // Shadows cast from the first light, if any
+    pub shadow : Option<&'a ShadowMap>,
+    pub ambient : f32,
+    pub phong_exp : f32
+}
+
+impl Shader for NormalMapShader<'_> {
+    type Varyings = NormalMapVaryings;
+
+    fn vertex(&self, i : u32) -> (Vector4<f32>, NormalMapVaryings) {
+        let idx = i as usize;
+        let n = Vector3::new(self.normals[idx*3], self.normals[idx*3+1], self.normals[idx*3+2]);
+        let t = Vector3::new(self.tangents[idx*4], self.tangents[idx*4+1], self.tangents[idx*4+2]);
+        let v = Vector4::new(self.positions[idx*3], self.positions[idx*3+1], self.positions[idx*3+2], 1.);
+        let tc = Vector2::new(self.texcoords[idx*2], self.texcoords[idx*2 + 1]);
+        let vary = NormalMapVaryings {
+            tc,
+            normal : self.model * n,
+            tangent : self.model * t,
+            sign : Flat(self.tangents[idx*4+3]),
+            position : (self.world * v).xyz()
+        };
+        (self.mvp * v, vary)
+    }
+
+    fn fragment(&self, vary : &NormalMapVaryings, _input : &FragmentInput<NormalMapVaryings>) -> FragmentOutput {
+        let (n, t) = (vary.normal, vary.tangent);
+        let b = n.cross(&t) * vary.sign.0;
+        // Normal map texels hold the tangent space normal scaled from -1..1 to 0..255
+        let m = texel(self.normal_map, &vary.tc);
+        let m = Vector3::new(m[0] as f32, m[1] as f32, m[2] as f32) / 127.5 - Vector3::repeat(1.);
+        // model in left hand coord, flip x y z val
+        let n = -(t * m.x + b * m.y + n * m.z).normalize();
+        let e = (self.eye - vary.position).normalize();
+        let visibility = shadow_visibility(self.shadow, &vary.position);
+        let (diffuse_int, spec_int) = blinn_phong_intensity(&n, &e, self.light_source, self.phong_exp, visibility);
+        let diffuse_color = texel(self.diffuse, &vary.tc);
+        let spec_color = texel(self.spec, &vary.tc);
+        FragmentOutput::color(calc_blinnphong_color(diffuse_int, spec_int, self.ambient, diffuse_color, spec_color))
+    }
+
+}
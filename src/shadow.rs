@@ -0,0 +1,178 @@
+use nalgebra::{Matrix4, Vector3, Vector4};
+use super::framebuffer::{Framebuffer, SampleCount};
+use super::render;
+use super::shader::{FragmentInput, FragmentOutput, Shader};
+use super::state::{DepthBias, DepthState, PipelineState};
+use super::stats::DrawStats;
+use super::transforms;
+
+// Depth-only shader for rendering shadow casters
+struct DepthShader<'a> {
+    mvp : Matrix4<f32>,
+    positions : &'a [f32]
+}
+
+impl Shader for DepthShader<'_> {
+    type Varyings = ();
+
+    fn vertex(&self, i : u32) -> (Vector4<f32>, ()) {
+        let idx = i as usize;
+        let v = Vector4::new(self.positions[idx*3], self.positions[idx*3+1], self.positions[idx*3+2], 1.);
+        (self.mvp * v, ())
+    }
+
+    fn fragment(&self, _v : &(), _input : &FragmentInput<()>) -> FragmentOutput {
+        FragmentOutput::rgba(Vector4::zeros())
+    }
+}
+
+// Some unit vector that is not parallel to d, used as the up direction of a light
+fn up_for(d : &Vector3<f32>) -> Vector3<f32> {
+    if d.normalize().y.abs() < 0.99 {Vector3::y()} else {Vector3::x()}
+}
+
+// The scene's depth as seen from a light, rendered with render::rasterize into a
+// depth-only framebuffer. A point is lit when it is no farther from the light than
+// the closest caster at its texel, less bias. slope_bias pushes stored depth away from
+// the light by that many times its depth slope while rendering, against shadow acne
+// on surfaces at grazing angles. Lookups average (2 * pcf_radius + 1)^2 texels. The
+// outer texels are farther from the point and draw does not scale the bias, so callers
+// raising pcf_radius should raise slope_bias with it
+pub struct ShadowMap {
+    // World space to the light's clip space
+    pub view_proj : Matrix4<f32>,
+    pub bias : f32,
+    pub slope_bias : f32,
+    pub pcf_radius : u32,
+    fb : Framebuffer
+}
+
+impl ShadowMap {
+    pub fn new(view_proj : Matrix4<f32>, size : u32) -> ShadowMap {
+        ShadowMap { view_proj, bias : 0.001, slope_bias : 2.5, pcf_radius : 1, fb : Framebuffer::new(size, size, SampleCount::X1) }
+    }
+
+    // A directional light shining along direction, covering a sphere of the given
+    // radius around center
+    pub fn directional(direction : Vector3<f32>, center : Vector3<f32>, radius : f32, size : u32) -> ShadowMap {
+        let eye = center - direction.normalize() * radius;
+        let view = transforms::camera(eye, direction, up_for(&direction));
+        let proj = transforms::orthographic(-radius, radius, -radius, radius, 0., -2. * radius);
+        ShadowMap::new(proj * view, size)
+    }
+
+    // A spot light at position shining along direction, with a cone of angle radians
+    // and casters between near and far from the light
+    pub fn spot(position : Vector3<f32>, direction : Vector3<f32>, angle : f32, near : f32, far : f32, size : u32) -> ShadowMap {
+        let view = transforms::camera(position, direction, up_for(&direction));
+        let s = near * (angle / 2.).tan();
+        let proj = transforms::perspective(-s, s, -s, s, -near, -far);
+        ShadowMap::new(proj * view, size)
+    }
+
+    pub fn size(&self) -> u32 {
+        self.fb.width
+    }
+
+    // Remove every caster
+    pub fn clear(&mut self) {
+        self.fb.clear_depth_for(&DepthState::default());
+    }
+
+    // Render an indexed triangle list as shadow casters, world takes its positions
+    // to world space. Both faces cast shadows
+    pub fn draw(&mut self, indices : &[u32], positions : &[f32], world : &Matrix4<f32>) -> DrawStats {
+        let shader = DepthShader { mvp : self.view_proj * world, positions };
+        let state = PipelineState {
+            depth_bias : DepthBias { constant : 0., slope : -self.slope_bias, clamp : 0. },
+            ..Default::default()
+        };
+        render::rasterize_indexed(indices, &shader, &state, &mut self.fb)
+    }
+
+    // Fraction of the filter footprint around a world space point that is lit, points
+    // outside the light's view are lit
+    pub fn visibility(&self, position : &Vector3<f32>) -> f32 {
+        let p = self.view_proj * position.push(1.);
+        if p.w <= 0. {
+            return 1.;
+        }
+        let ndc = p.xyz() / p.w;
+        if ndc.x.abs() > 1. || ndc.y.abs() > 1. || ndc.z.abs() > 1. {
+            return 1.;
+        }
+        // Same mapping to window depth as the default viewport
+        let z = (ndc.z + 1.) / 2. + self.bias;
+        let size = self.fb.width as i64;
+        let x = ((ndc.x + 1.) / 2. * size as f32).floor() as i64;
+        let y = ((ndc.y + 1.) / 2. * size as f32).floor() as i64;
+        let r = self.pcf_radius as i64;
+        let mut lit = 0;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let tx = (x + dx).clamp(0, size - 1);
+                let ty = (y + dy).clamp(0, size - 1);
+                if z >= self.fb.depth()[(tx + ty * size) as usize] {
+                    lit += 1;
+                }
+            }
+        }
+        lit as f32 / ((2 * r + 1) * (2 * r + 1)) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where a point at x, y lies on a square receiver tilted along x below the origin
+    fn receiver(x : f32, y : f32) -> Vector3<f32> {
+        Vector3::new(x, y, -1. + 0.3 * x)
+    }
+
+    // The receiver reaching 2 from the z axis, and a square occluder at z = 0 with the
+    // given half size
+    fn draw_scene(map : &mut ShadowMap, occluder : f32) {
+        let mut positions = Vec::new();
+        for (x, y) in [(-2., -2.), (2., -2.), (2., 2.), (-2., 2.)].iter() {
+            positions.extend_from_slice(receiver(*x, *y).as_slice());
+        }
+        for (x, y) in [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)].iter() {
+            positions.extend_from_slice(&[x * occluder, y * occluder, 0.]);
+        }
+        map.draw(&[0, 1, 2, 0, 2, 3, 4, 5, 6, 4, 6, 7], &positions, &Matrix4::identity());
+    }
+
+    // Points on the occluder, and on the receiver along y = 1 away from the shadow are
+    // lit without acne, the receiver at the origin is in shadow, and the filter
+    // footprint at edge straddles the edge of the shadow
+    fn check_visibility(map : &ShadowMap, edge : Vector3<f32>) {
+        assert_eq!(map.visibility(&Vector3::new(0.2, 0.1, 0.)), 1.);
+        for i in 0..40 {
+            let x = -1. + i as f32 * 0.05;
+            assert_eq!(map.visibility(&receiver(x, 1.)), 1., "receiver at ({}, 1)", x);
+        }
+        assert_eq!(map.visibility(&receiver(0., 0.)), 0.);
+        let v = map.visibility(&edge);
+        assert!(v > 0. && v < 1., "visibility {}", v);
+    }
+
+    #[test]
+    fn directional_shadows_match_the_occluder() {
+        let mut map = ShadowMap::directional(Vector3::new(0., 0., -1.), Vector3::new(0., 0., -1.), 3., 128);
+        draw_scene(&mut map, 0.5);
+        // Parallel light casts a shadow of the same size
+        check_visibility(&map, receiver(0.5, 0.));
+    }
+
+    #[test]
+    fn spot_shadows_grow_with_distance_from_the_light() {
+        let mut map = ShadowMap::spot(Vector3::new(0., 0., 1.), Vector3::new(0., 0., -1.), 1.4, 0.1, 10., 256);
+        draw_scene(&mut map, 0.25);
+        // From the light at z = 1 the occluder edge x = 0.25 projects to x = 0.5 / 1.075
+        // on the receiver, about twice as far out as the occluder
+        check_visibility(&map, receiver(0.5 / 1.075, 0.));
+        // Outside the cone counts as lit
+        assert_eq!(map.visibility(&Vector3::new(5., 0., -1.)), 1.);
+    }
+}
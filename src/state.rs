@@ -0,0 +1,419 @@
+use nalgebra::Vector4;
+
+// How consecutive vertices of a draw are assembled into primitives. Strips share the
+// last two vertices of the previous triangle and fans share the first vertex of the draw
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PrimitiveTopology {
+    TriangleList,
+    TriangleStrip,
+    TriangleFan,
+    LineList,
+    LineStrip,
+    PointList
+}
+
+impl PrimitiveTopology {
+    // Number of complete primitives in a draw of count vertices
+    pub fn primitive_count(self, count : usize) -> usize {
+        match self {
+            PrimitiveTopology::TriangleList => count / 3,
+            PrimitiveTopology::TriangleStrip | PrimitiveTopology::TriangleFan => count.saturating_sub(2),
+            PrimitiveTopology::LineList => count / 2,
+            PrimitiveTopology::LineStrip => count.saturating_sub(1),
+            PrimitiveTopology::PointList => count
+        }
+    }
+}
+
+// Which faces to discard before rasterization
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CullMode {
+    None,
+    Back,
+    Front
+}
+
+// Winding order of front facing triangles in screen space (y pointing up)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrontFace {
+    CounterClockwise,
+    Clockwise
+}
+
+// Comparison used by the depth test, the incoming value is compared against the
+// stored one
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    LessEqual,
+    Equal,
+    Greater,
+    GreaterEqual,
+    NotEqual,
+    Always
+}
+
+impl CompareFunc {
+    pub fn test<T : PartialOrd>(self, incoming : T, stored : T) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => incoming < stored,
+            CompareFunc::LessEqual => incoming <= stored,
+            CompareFunc::Equal => incoming == stored,
+            CompareFunc::Greater => incoming > stored,
+            CompareFunc::GreaterEqual => incoming >= stored,
+            CompareFunc::NotEqual => incoming != stored,
+            CompareFunc::Always => true
+        }
+    }
+}
+
+// Window depth runs from the viewport's min_depth at the far plane to max_depth at the
+// near plane, so closer fragments have greater depth. reversed_z expects normalized
+// depth from 0 at the far plane rather than -1, as produced by
+// transforms::perspective_reversed_z, which keeps far away geometry in the precise
+// range of f32. With hierarchical set, blocks of pixels whose stored depth already
+// hides a primitive are skipped before any per-pixel work. Framebuffer::clear_depth_for
+// clears depth to clear_value, the farthest depth under compare
+#[derive(Clone, Copy, Debug)]
+pub struct DepthState {
+    pub compare : CompareFunc,
+    pub write : bool,
+    pub clear_value : f32,
+    pub reversed_z : bool,
+    pub hierarchical : bool
+}
+
+impl DepthState {
+    pub fn reversed_z() -> DepthState {
+        DepthState {
+            compare : CompareFunc::Greater,
+            write : true,
+            clear_value : 0.,
+            reversed_z : true,
+            hierarchical : true
+        }
+    }
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        DepthState {
+            compare : CompareFunc::Greater,
+            write : true,
+            clear_value : f32::MIN,
+            reversed_z : false,
+            hierarchical : true
+        }
+    }
+}
+
+// Offset added to the window depth of a primitive's fragments before the depth test,
+// like glPolygonOffset. It is constant times the smallest f32 step at the primitive's
+// largest depth plus slope times its largest depth change per pixel in x or y, and
+// has at most the magnitude of clamp unless clamp is 0. With the default Greater
+// compare positive values move fragments towards the viewer
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct DepthBias {
+    pub constant : f32,
+    pub slope : f32,
+    pub clamp : f32
+}
+
+// Operation applied to a stored stencil value
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StencilOp {
+    Keep,
+    Zero,
+    Replace,
+    IncrClamp,
+    DecrClamp,
+    IncrWrap,
+    DecrWrap,
+    Invert
+}
+
+impl StencilOp {
+    pub fn apply(self, value : u8, reference : u8) -> u8 {
+        match self {
+            StencilOp::Keep => value,
+            StencilOp::Zero => 0,
+            StencilOp::Replace => reference,
+            StencilOp::IncrClamp => value.saturating_add(1),
+            StencilOp::DecrClamp => value.saturating_sub(1),
+            StencilOp::IncrWrap => value.wrapping_add(1),
+            StencilOp::DecrWrap => value.wrapping_sub(1),
+            StencilOp::Invert => !value
+        }
+    }
+}
+
+// Stencil test and operations for one facing. fail runs when the stencil test fails,
+// depth_fail when the stencil test passes but the depth test fails, pass when both pass
+#[derive(Clone, Copy, Debug)]
+pub struct StencilFace {
+    pub compare : CompareFunc,
+    pub fail : StencilOp,
+    pub depth_fail : StencilOp,
+    pub pass : StencilOp
+}
+
+impl Default for StencilFace {
+    fn default() -> Self {
+        StencilFace {
+            compare : CompareFunc::Always,
+            fail : StencilOp::Keep,
+            depth_fail : StencilOp::Keep,
+            pass : StencilOp::Keep
+        }
+    }
+}
+
+// The test compares (reference & read_mask) against (stored & read_mask), only the
+// bits in write_mask are updated. Back facing triangles use the back face state
+#[derive(Clone, Copy, Debug)]
+pub struct StencilState {
+    pub enabled : bool,
+    pub reference : u8,
+    pub read_mask : u8,
+    pub write_mask : u8,
+    pub front : StencilFace,
+    pub back : StencilFace
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        StencilState {
+            enabled : false,
+            reference : 0,
+            read_mask : 0xff,
+            write_mask : 0xff,
+            front : StencilFace::default(),
+            back : StencilFace::default()
+        }
+    }
+}
+
+// Multiplier applied to the source or destination colour before they are combined
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendFactor {
+    Zero,
+    One,
+    SrcColor,
+    OneMinusSrcColor,
+    DstColor,
+    OneMinusDstColor,
+    SrcAlpha,
+    OneMinusSrcAlpha,
+    DstAlpha,
+    OneMinusDstAlpha
+}
+
+impl BlendFactor {
+    fn factor(self, src : &Vector4<f32>, dst : &Vector4<f32>) -> Vector4<f32> {
+        let one = Vector4::repeat(1.);
+        match self {
+            BlendFactor::Zero => Vector4::zeros(),
+            BlendFactor::One => one,
+            BlendFactor::SrcColor => *src,
+            BlendFactor::OneMinusSrcColor => one - src,
+            BlendFactor::DstColor => *dst,
+            BlendFactor::OneMinusDstColor => one - dst,
+            BlendFactor::SrcAlpha => Vector4::repeat(src.w),
+            BlendFactor::OneMinusSrcAlpha => Vector4::repeat(1. - src.w),
+            BlendFactor::DstAlpha => Vector4::repeat(dst.w),
+            BlendFactor::OneMinusDstAlpha => Vector4::repeat(1. - dst.w)
+        }
+    }
+}
+
+// How the weighted source and destination are combined
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BlendOp {
+    Add,
+    Subtract,
+    ReverseSubtract,
+    Min,
+    Max
+}
+
+impl BlendOp {
+    fn apply(self, src : f32, dst : f32) -> f32 {
+        match self {
+            BlendOp::Add => src + dst,
+            BlendOp::Subtract => src - dst,
+            BlendOp::ReverseSubtract => dst - src,
+            BlendOp::Min => src.min(dst),
+            BlendOp::Max => src.max(dst)
+        }
+    }
+}
+
+// Colour blending, rgb and alpha have separate factors and operations. Min and Max
+// ignore the factors. When disabled the fragment colour replaces the stored one
+#[derive(Clone, Copy, Debug)]
+pub struct BlendState {
+    pub enabled : bool,
+    pub src_color : BlendFactor,
+    pub dst_color : BlendFactor,
+    pub color_op : BlendOp,
+    pub src_alpha : BlendFactor,
+    pub dst_alpha : BlendFactor,
+    pub alpha_op : BlendOp
+}
+
+impl BlendState {
+    fn with_factors(src : BlendFactor, dst : BlendFactor) -> BlendState {
+        BlendState {
+            enabled : true,
+            src_color : src,
+            dst_color : dst,
+            color_op : BlendOp::Add,
+            src_alpha : src,
+            dst_alpha : dst,
+            alpha_op : BlendOp::Add
+        }
+    }
+
+    // Classic transparency, src * a + dst * (1 - a)
+    pub fn alpha() -> BlendState {
+        let mut state = BlendState::with_factors(BlendFactor::SrcAlpha, BlendFactor::OneMinusSrcAlpha);
+        state.src_alpha = BlendFactor::One;
+        state
+    }
+
+    // Source colour already multiplied by its alpha
+    pub fn premultiplied() -> BlendState {
+        BlendState::with_factors(BlendFactor::One, BlendFactor::OneMinusSrcAlpha)
+    }
+
+    pub fn additive() -> BlendState {
+        BlendState::with_factors(BlendFactor::One, BlendFactor::One)
+    }
+
+    pub fn multiply() -> BlendState {
+        BlendState::with_factors(BlendFactor::DstColor, BlendFactor::Zero)
+    }
+
+    pub fn blend(&self, src : &Vector4<f32>, dst : &Vector4<f32>) -> Vector4<f32> {
+        if !self.enabled {
+            return *src;
+        }
+        let s = src.component_mul(&self.src_color.factor(src, dst));
+        let d = dst.component_mul(&self.dst_color.factor(src, dst));
+        let sa = src.w * self.src_alpha.factor(src, dst).w;
+        let da = dst.w * self.dst_alpha.factor(src, dst).w;
+        let (s, d) = match self.color_op {
+            BlendOp::Min | BlendOp::Max => (*src, *dst),
+            _ => (s, d)
+        };
+        let (sa, da) = match self.alpha_op {
+            BlendOp::Min | BlendOp::Max => (src.w, dst.w),
+            _ => (sa, da)
+        };
+        Vector4::new(self.color_op.apply(s.x, d.x), self.color_op.apply(s.y, d.y), self.color_op.apply(s.z, d.z), self.alpha_op.apply(sa, da))
+    }
+}
+
+impl Default for BlendState {
+    fn default() -> Self {
+        let mut state = BlendState::with_factors(BlendFactor::One, BlendFactor::Zero);
+        state.enabled = false;
+        state
+    }
+}
+
+// Maps normalized device coordinates onto a rectangle of the framebuffer, in pixels
+// from its bottom left corner. Depth runs from min_depth at the far plane to
+// max_depth at the near plane
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Viewport {
+    pub x : f32,
+    pub y : f32,
+    pub width : f32,
+    pub height : f32,
+    pub min_depth : f32,
+    pub max_depth : f32
+}
+
+impl Viewport {
+    // Covers a whole framebuffer with the full depth range
+    pub fn new(width : u32, height : u32) -> Viewport {
+        Viewport { x : 0., y : 0., width : width as f32, height : height as f32, min_depth : 0., max_depth : 1. }
+    }
+}
+
+// Pixels outside this rectangle are never written, in pixels from the bottom left
+// corner of the framebuffer
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Scissor {
+    pub x : u32,
+    pub y : u32,
+    pub width : u32,
+    pub height : u32
+}
+
+// Fixed function state of a single draw
+#[derive(Clone, Copy, Debug)]
+pub struct PipelineState {
+    pub topology : PrimitiveTopology,
+    // None covers the whole framebuffer
+    pub viewport : Option<Viewport>,
+    pub scissor : Option<Scissor>,
+    pub cull_mode : CullMode,
+    pub front_face : FrontFace,
+    // Overestimating conservative rasterization, triangles generate every pixel they
+    // touch with all of its samples covered
+    pub conservative : bool,
+    pub depth : DepthState,
+    pub depth_bias : DepthBias,
+    pub stencil : StencilState,
+    pub blend : BlendState,
+    // Number of worker threads, 0 uses every available core
+    pub threads : usize
+}
+
+impl Default for PipelineState {
+    fn default() -> Self {
+        PipelineState {
+            topology : PrimitiveTopology::TriangleList,
+            viewport : None,
+            scissor : None,
+            cull_mode : CullMode::None,
+            front_face : FrontFace::CounterClockwise,
+            conservative : false,
+            depth : DepthState::default(),
+            depth_bias : DepthBias::default(),
+            stencil : StencilState::default(),
+            blend : BlendState::default(),
+            threads : 0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every value here and in the results is exact in f32
+    #[test]
+    fn blend_presets_combine_source_and_destination() {
+        let src = Vector4::new(0.5, 0.25, 1., 0.5);
+        let dst = Vector4::new(0.25, 0.5, 0.5, 0.75);
+        assert_eq!(BlendState::default().blend(&src, &dst), src);
+        assert_eq!(BlendState::alpha().blend(&src, &dst), Vector4::new(0.375, 0.375, 0.75, 0.875));
+        assert_eq!(BlendState::premultiplied().blend(&src, &dst), Vector4::new(0.625, 0.5, 1.25, 0.875));
+        assert_eq!(BlendState::additive().blend(&src, &dst), Vector4::new(0.75, 0.75, 1.5, 1.25));
+        assert_eq!(BlendState::multiply().blend(&src, &dst), Vector4::new(0.125, 0.125, 0.5, 0.375));
+
+        // Min and Max ignore the factors, even ones that would zero both sides
+        let mut state = BlendState::with_factors(BlendFactor::Zero, BlendFactor::Zero);
+        state.color_op = BlendOp::Min;
+        state.alpha_op = BlendOp::Max;
+        assert_eq!(state.blend(&src, &dst), Vector4::new(0.25, 0.25, 0.5, 0.75));
+        state.color_op = BlendOp::Max;
+        state.alpha_op = BlendOp::Min;
+        assert_eq!(state.blend(&src, &dst), Vector4::new(0.5, 0.5, 1., 0.5));
+    }
+}
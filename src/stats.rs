@@ -0,0 +1,140 @@
+use std::ops::AddAssign;
+use std::time::Duration;
+
+// Counters collected during a draw, returned by render::rasterize. Adding the stats of
+// several draws sums every counter and time
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct DrawStats {
+    // Vertices run through the vertex shader, each referenced vertex counts once
+    pub vertices_shaded : u64,
+    // Primitives assembled from the vertex or index buffer
+    pub primitives_in : u64,
+    // Primitives handed to the rasterizer, clipping may split one into several
+    pub primitives_out : u64,
+    // Pixels with at least one covered sample inside the depth range, without the pixels
+    // of blocks hierarchical depth skipped
+    pub fragments_generated : u64,
+    // Blocks of pixels hierarchical depth skipped a primitive in as it was hidden there,
+    // once for every primitive
+    pub hiz_culled : u64,
+    // Fragment shader invocations, fewer than generated when early depth tests fail
+    pub fragments_shaded : u64,
+    // Fragments with a sample passing the depth and stencil tests that were not dropped
+    pub fragments_passed : u64,
+    // Samples passing the depth and stencil tests, what an occlusion query counts
+    pub samples_passed : u64,
+    // Triangles with zero area once snapped to the sub-pixel grid, or lines of zero length
+    pub degenerate : u64,
+    // Primitives with a NaN or infinite window coordinate
+    pub non_finite : u64,
+    // Primitives reaching outside the guard band the fixed point rasterizer can represent.
+    // Clipping keeps primitives inside it, so only viewports reaching outside the guard
+    // band themselves get here
+    pub out_of_range : u64,
+    pub culled : u64,
+    // Primitives entirely beyond the near or far plane, the guard band, or outside the
+    // drawable area
+    pub clipped : u64,
+    // Wall clock time of the vertex stage, primitive assembly and setup, binning and the
+    // back end
+    pub vertex_time : Duration,
+    pub setup_time : Duration,
+    pub binning_time : Duration,
+    pub raster_time : Duration
+}
+
+// Why primitive setup dropped a primitive
+pub(crate) enum Reject {
+    Degenerate,
+    NonFinite,
+    OutOfRange,
+    Culled,
+    Clipped
+}
+
+impl DrawStats {
+    pub(crate) fn reject(&mut self, reason : Reject) {
+        match reason {
+            Reject::Degenerate => self.degenerate += 1,
+            Reject::NonFinite => self.non_finite += 1,
+            Reject::OutOfRange => self.out_of_range += 1,
+            Reject::Culled => self.culled += 1,
+            Reject::Clipped => self.clipped += 1
+        }
+    }
+
+    // Count a generated fragment, whether it was shaded and the samples it wrote
+    pub(crate) fn fragment(&mut self, shaded : bool, passed : u32) {
+        self.fragments_generated += 1;
+        self.fragments_shaded += shaded as u64;
+        self.fragments_passed += (passed != 0) as u64;
+        self.samples_passed += passed.count_ones() as u64;
+    }
+
+    // Total number of primitives dropped before rasterization
+    pub fn rejected(&self) -> u64 {
+        self.degenerate + self.non_finite + self.out_of_range + self.culled + self.clipped
+    }
+}
+
+impl AddAssign for DrawStats {
+    fn add_assign(&mut self, other : DrawStats) {
+        self.vertices_shaded += other.vertices_shaded;
+        self.primitives_in += other.primitives_in;
+        self.primitives_out += other.primitives_out;
+        self.fragments_generated += other.fragments_generated;
+        self.hiz_culled += other.hiz_culled;
+        self.fragments_shaded += other.fragments_shaded;
+        self.fragments_passed += other.fragments_passed;
+        self.samples_passed += other.samples_passed;
+        self.degenerate += other.degenerate;
+        self.non_finite += other.non_finite;
+        self.out_of_range += other.out_of_range;
+        self.culled += other.culled;
+        self.clipped += other.clipped;
+        self.vertex_time += other.vertex_time;
+        self.setup_time += other.setup_time;
+        self.binning_time += other.binning_time;
+        self.raster_time += other.raster_time;
+    }
+}
+
+// Counts the samples that pass the depth and stencil tests across the draws recorded
+// between begin and end. Drawing a bounding volume with depth writes off and reading
+// the result tells whether anything inside it would be visible
+#[derive(Clone, Copy, Default, Debug)]
+pub struct OcclusionQuery {
+    samples : u64,
+    active : bool
+}
+
+impl OcclusionQuery {
+    pub fn new() -> OcclusionQuery {
+        OcclusionQuery::default()
+    }
+
+    // Start counting from zero
+    pub fn begin(&mut self) {
+        self.samples = 0;
+        self.active = true;
+    }
+
+    // Add the samples of a draw, draws recorded outside begin and end are ignored
+    pub fn record(&mut self, stats : &DrawStats) {
+        if self.active {
+            self.samples += stats.samples_passed;
+        }
+    }
+
+    pub fn end(&mut self) {
+        self.active = false;
+    }
+
+    pub fn samples_passed(&self) -> u64 {
+        self.samples
+    }
+
+    pub fn any_samples_passed(&self) -> bool {
+        self.samples > 0
+    }
+}
@@ -0,0 +1,109 @@
+use nalgebra::{Vector2, Vector3};
+
+fn vec3(data : &[f32], i : usize) -> Vector3<f32> {
+    Vector3::new(data[i*3], data[i*3+1], data[i*3+2])
+}
+
+// Some unit vector perpendicular to n
+fn perpendicular(n : &Vector3<f32>) -> Vector3<f32> {
+    let axis = if n.x.abs() < 0.9 {Vector3::x()} else {Vector3::y()};
+    (axis - n * n.dot(&axis)).normalize()
+}
+
+// Per-vertex tangents of an indexed triangle list in the MikkTSpace convention, four
+// floats per vertex. xyz is the unit tangent, orthogonal to the normal, pointing along
+// increasing u, and w is the sign of the bitangent, which is w * cross(normal, tangent)
+// and points along increasing v. Every triangle adds the tangent and bitangent implied
+// by its texture coordinates to its vertices, projected onto the plane of the vertex
+// normal and weighted by the angle of the triangle at that vertex. Positions, normals
+// and texture coordinates share the index buffer, as tobj loads them with single_index
+pub fn generate(indices : &[u32], positions : &[f32], normals : &[f32], texcoords : &[f32]) -> Vec<f32> {
+    let count = positions.len() / 3;
+    let mut tangents = vec![Vector3::zeros(); count];
+    let mut bitangents = vec![Vector3::zeros(); count];
+    for f in indices.chunks_exact(3) {
+        let idx = [f[0] as usize, f[1] as usize, f[2] as usize];
+        let p = idx.map(|i| vec3(positions, i));
+        let uv = idx.map(|i| Vector2::new(texcoords[i*2], texcoords[i*2+1]));
+        let (e1, e2) = (p[1] - p[0], p[2] - p[0]);
+        let (d1, d2) = (uv[1] - uv[0], uv[2] - uv[0]);
+        let det = d1.x * d2.y - d2.x * d1.y;
+        if det.abs() < f32::EPSILON {
+            continue;
+        }
+        let t = (e1 * d2.y - e2 * d1.y) / det;
+        let b = (e2 * d1.x - e1 * d2.x) / det;
+        for k in 0..3 {
+            let i = idx[k];
+            let n = vec3(normals, i).normalize();
+            let (a, c) = (p[(k + 1) % 3] - p[k], p[(k + 2) % 3] - p[k]);
+            let angle = a.angle(&c);
+            if !angle.is_finite() {
+                continue;
+            }
+            let t = t - n * n.dot(&t);
+            let b = b - n * n.dot(&b);
+            if t.norm() > 0. {
+                tangents[i] += t.normalize() * angle;
+            }
+            if b.norm() > 0. {
+                bitangents[i] += b.normalize() * angle;
+            }
+        }
+    }
+
+    let mut out = Vec::with_capacity(count * 4);
+    for i in 0..count {
+        let n = vec3(normals, i).normalize();
+        let t = tangents[i] - n * n.dot(&tangents[i]);
+        // Vertices without usable texture coordinates get an arbitrary tangent
+        let t = if t.norm() > f32::EPSILON {t.normalize()} else {perpendicular(&n)};
+        let w = if n.cross(&t).dot(&bitangents[i]) < 0. {-1.} else {1.};
+        out.extend_from_slice(&[t.x, t.y, t.z, w]);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INDICES : [u32; 6] = [0, 1, 2, 0, 2, 3];
+
+    // A unit quad in the xy plane facing +z, with u from the given function of x and v = y
+    fn quad(u : fn(f32) -> f32) -> Vec<f32> {
+        let corners = [(0., 0.), (1., 0.), (1., 1.), (0., 1.)];
+        let positions : Vec<f32> = corners.iter().flat_map(|&(x, y)| vec![x, y, 0.]).collect();
+        let normals : Vec<f32> = corners.iter().flat_map(|_| vec![0., 0., 1.]).collect();
+        let texcoords : Vec<f32> = corners.iter().flat_map(|&(x, y)| vec![u(x), y]).collect();
+        generate(&INDICES, &positions, &normals, &texcoords)
+    }
+
+    fn assert_tangents(tangents : &[f32], expected : [f32; 4]) {
+        for (i, t) in tangents.chunks_exact(4).enumerate() {
+            for k in 0..4 {
+                assert!((t[k] - expected[k]).abs() < 1e-6, "vertex {}: {:?} != {:?}", i, t, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn tangents_follow_the_texture_coordinates() {
+        assert_tangents(&quad(|x| x), [1., 0., 0., 1.]);
+        // Mirrored u flips the tangent but not the bitangent, so the sign flips too
+        assert_tangents(&quad(|x| 1. - x), [-1., 0., 0., -1.]);
+    }
+
+    #[test]
+    fn degenerate_texture_coordinates_fall_back_to_a_perpendicular() {
+        // A quad in the yz plane facing +x, with every vertex at the same uv
+        let positions = [0., 0., 0., 0., 1., 0., 0., 1., 1., 0., 0., 1.];
+        let normals = [1., 0., 0.].repeat(4);
+        let texcoords = [0.5; 8];
+        let tangents = generate(&INDICES, &positions, &normals, &texcoords);
+        let n = Vector3::x();
+        let t = perpendicular(&n);
+        assert!(t.dot(&n).abs() < 1e-6 && (t.norm() - 1.).abs() < 1e-6);
+        assert_tangents(&tangents, [t.x, t.y, t.z, 1.]);
+    }
+}
@@ -1,16 +1,5 @@
 use nalgebra::{Matrix4, Vector3};
 
-pub fn viewport(x : u32, y : u32) -> Matrix4<f32> {
-    let mut m = Matrix4::<f32>::identity();
-    let x = x as f32;
-    let y = y as f32;
-    m[(0,0)] = x / 2.;
-    m[(0,3)] = (x - 1.) / 2.;
-    m[(1,1)] = y / 2.;
-    m[(1,3)] = (y - 1.) / 2.;
-    m
-}
-
 pub fn camera(e : Vector3<f32>, g : Vector3<f32>, t : Vector3<f32>) -> Matrix4<f32> {
     let w = -g / Vector3::<f32>::norm(&g);
     let txw = t.cross(&w);
@@ -27,9 +16,29 @@ pub fn camera(e : Vector3<f32>, g : Vector3<f32>, t : Vector3<f32>) -> Matrix4<f
     m1 * m2
 }
 
+// Negated so that w = -z is positive in front of the camera, this gives the same
+// projected point but lets the pipeline clip against -w <= z <= w
 pub fn perspective(l : f32, r : f32, b : f32, t : f32, n : f32, f : f32) -> Matrix4<f32> {
-    Matrix4::<f32>::new(2.*n/(r-l), 0., (l+r)/(l-r), 0.,
-                        0.,  2.*n/(t-b), (b+t)/(b-t), 0.,
-                        0., 0., (f+n)/(n-f), 2.*f*n/(f-n),
-                        0., 0., 1., 0.)
-}
\ No newline at end of file
+    Matrix4::<f32>::new(-2.*n/(r-l), 0., (l+r)/(r-l), 0.,
+                        0.,  -2.*n/(t-b), (b+t)/(t-b), 0.,
+                        0., 0., (f+n)/(f-n), 2.*f*n/(n-f),
+                        0., 0., -1., 0.)
+}
+
+// Same frustum as perspective, but depth goes from 1 at the near plane to 0 at the far
+// plane, for use with DepthState::reversed_z
+pub fn perspective_reversed_z(l : f32, r : f32, b : f32, t : f32, n : f32, f : f32) -> Matrix4<f32> {
+    Matrix4::<f32>::new(-2.*n/(r-l), 0., (l+r)/(r-l), 0.,
+                        0.,  -2.*n/(t-b), (b+t)/(t-b), 0.,
+                        0., 0., n/(f-n), f*n/(n-f),
+                        0., 0., -1., 0.)
+}
+
+// Orthographic projection of the box l..r, b..t, n..f with the same conventions as
+// perspective, depth goes from 1 at the near plane to -1 at the far plane
+pub fn orthographic(l : f32, r : f32, b : f32, t : f32, n : f32, f : f32) -> Matrix4<f32> {
+    Matrix4::<f32>::new(2./(r-l), 0., 0., -(r+l)/(r-l),
+                        0., 2./(t-b), 0., -(t+b)/(t-b),
+                        0., 0., 2./(n-f), -(n+f)/(n-f),
+                        0., 0., 0., 1.)
+}
@@ -0,0 +1,92 @@
+use nalgebra::{Vector2, Vector3, Vector4};
+
+// Weights of the vertices of a primitive at a point. perspective is corrected for the
+// 1/w of the vertices, linear is affine in screen space, and both add up to 1. The
+// provoking vertex is the first vertex of a primitive as it was submitted
+#[derive(Clone, Copy, Debug)]
+pub struct Barycentrics {
+    pub perspective : (f32, f32, f32),
+    pub linear : (f32, f32, f32),
+    pub provoking : usize
+}
+
+impl Barycentrics {
+    // From screen space barycentrics and the 1/w of the vertices, with vertex 0 provoking
+    pub(crate) fn new(bc : (f32, f32, f32), ws : (f32, f32, f32)) -> Barycentrics {
+        let (a, b, c) = (bc.0 * ws.0, bc.1 * ws.1, bc.2 * ws.2);
+        let sum = a + b + c;
+        Barycentrics { perspective : (a / sum, b / sum, c / sum), linear : bc, provoking : 0 }
+    }
+}
+
+// Values a vertex shader passes to the fragment shader. The pipeline only ever blends
+// them, when the clipper splits an edge and when a fragment is interpolated from the
+// vertices of its primitive. Plain values are interpolated perspective correctly,
+// wrap them in Flat or NoPerspective to change that
+pub trait Varyings : Clone + Send + Sync {
+    fn interpolate(vs : [&Self; 3], bc : &Barycentrics) -> Self;
+}
+
+// Shaders without varyings
+impl Varyings for () {
+    fn interpolate(_vs : [&Self; 3], _bc : &Barycentrics) -> Self {}
+}
+
+impl Varyings for f32 {
+    fn interpolate(vs : [&Self; 3], bc : &Barycentrics) -> Self {
+        let w = bc.perspective;
+        vs[0] * w.0 + vs[1] * w.1 + vs[2] * w.2
+    }
+}
+
+macro_rules! impl_varyings_vector {
+    ($t : ident) => {
+        impl Varyings for $t<f32> {
+            fn interpolate(vs : [&Self; 3], bc : &Barycentrics) -> Self {
+                let w = bc.perspective;
+                vs[0] * w.0 + vs[1] * w.1 + vs[2] * w.2
+            }
+        }
+    };
+}
+
+impl_varyings_vector!(Vector2);
+impl_varyings_vector!(Vector3);
+impl_varyings_vector!(Vector4);
+
+// Takes the value of the provoking vertex over the whole primitive, for values that
+// must not blend like object ids or faceted normals. Any type can be flat
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Flat<T>(pub T);
+
+impl<T : Clone + Send + Sync> Varyings for Flat<T> {
+    fn interpolate(vs : [&Self; 3], bc : &Barycentrics) -> Self {
+        vs[bc.provoking].clone()
+    }
+}
+
+// Interpolated linearly in screen space, without perspective correction
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NoPerspective<T>(pub T);
+
+impl<T : Varyings> Varyings for NoPerspective<T> {
+    fn interpolate(vs : [&Self; 3], bc : &Barycentrics) -> Self {
+        let bc = Barycentrics { perspective : bc.linear, ..*bc };
+        NoPerspective(T::interpolate([&vs[0].0, &vs[1].0, &vs[2].0], &bc))
+    }
+}
+
+// Implement Varyings for a struct whose fields all implement it, by interpolating
+// every field on its own:
+//     struct Out { uv : Vector2<f32>, light : f32, id : Flat<u32> }
+//     varyings!(Out { uv, light, id });
+#[macro_export]
+macro_rules! varyings {
+    ($t : ident { $($field : ident),* $(,)? }) => {
+        impl $crate::varyings::Varyings for $t {
+            fn interpolate(vs : [&Self; 3], bc : &$crate::varyings::Barycentrics) -> Self {
+                $t { $($field : $crate::varyings::Varyings::interpolate([&vs[0].$field, &vs[1].$field, &vs[2].$field], bc)),* }
+            }
+        }
+    };
+}
@@ -1,10 +1,53 @@
+use std::collections::HashSet;
+use nalgebra::{Vector4, Matrix4};
 use image::{Rgb, RgbImage};
+use super::shader::{FragmentInput, FragmentOutput, Shader};
 
+// Turn an indexed triangle list into a line list with every edge shared between
+// triangles drawn once, in the order the edges are first seen
+pub fn edges(indices : &[u32]) -> Vec<u32> {
+    let mut seen = HashSet::new();
+    let mut out = Vec::new();
+    for f in indices.chunks_exact(3) {
+        for j in 0..3 {
+            let (a, b) = (f[j], f[(j + 1) % 3]);
+            if seen.insert((a.min(b), a.max(b))) {
+                out.push(a);
+                out.push(b);
+            }
+        }
+    }
+    out
+}
+
+// Draws lines in a single colour, use with PrimitiveTopology::LineList, the indices from
+// edges and the transform of the shaded mesh so edges are depth tested against it
+pub struct WireframeShader<'a> {
+    pub mvp : Matrix4<f32>,
+    pub positions : &'a Vec<f32>,
+    pub color : Rgb<u8>
+}
+
+impl Shader for WireframeShader<'_> {
+    type Varyings = ();
+
+    fn vertex(&self, i : u32) -> (Vector4<f32>, ()) {
+        let idx = i as usize;
+        let v = Vector4::new(self.positions[idx*3], self.positions[idx*3+1], self.positions[idx*3+2], 1.);
+        (self.mvp * v, ())
+    }
+
+    fn fragment(&self, _v : &(), _input : &FragmentInput<()>) -> FragmentOutput {
+        FragmentOutput::color(self.color)
+    }
+
+}
+
+// Draw straight into an image without depth testing, bypassing the pipeline
 pub fn draw_line(x0 : u32, y0 : u32, x1 : u32, y1 : u32, img : &mut RgbImage, color : Rgb<u8>) {
     let width = img.width();
     let height = img.height();
-    let (mut x0, mut y0, mut x1, mut y1) = ((width - x0) as u32, (height - y0) as u32,
-     (width - x1) as u32, (height - y1) as u32);
+    let (mut x0, mut y0, mut x1, mut y1) = (width - x0, height - y0, width - x1, height - y1);
     let mut steep = false;
     if i64::abs(x0 as i64 - x1 as i64) < i64::abs(y0 as i64 - y1 as i64) {
         std::mem::swap(&mut x0, &mut y0);
@@ -20,12 +63,10 @@ pub fn draw_line(x0 : u32, y0 : u32, x1 : u32, y1 : u32, img : &mut RgbImage, co
         let y = ((y0 as f32 * (1.0 - t)) + y1 as f32 * t) as u32;
         if steep {
             if x < width && y < height {
-                img.put_pixel(y, x as u32, color)
-            }
-        } else {
-            if x < width && y < height {
-                img.put_pixel(x as u32, y, color)
+                img.put_pixel(y, x, color)
             }
+        } else if x < width && y < height {
+            img.put_pixel(x, y, color)
         }
     }
 }
@@ -39,7 +80,7 @@ pub fn draw_wireframe(models : Vec<tobj::Model>, img : &mut RgbImage) {
         let positions = model.mesh.positions;
         for i in 0..(indices.len() / 3) {
             let f = [indices[i*3], indices[i*3+1], indices[i*3+2]];
-            for j in 0 as usize..3 as usize {
+            for j in 0..3 {
                 let v0 = (positions[(f[j] * 3) as usize], positions[(f[j] * 3 + 1) as usize],
                     positions[(f[j] * 3 + 2) as usize]);
                 let v1i = (j + 1) % 3;
@@ -53,4 +94,4 @@ pub fn draw_wireframe(models : Vec<tobj::Model>, img : &mut RgbImage) {
             }
         }
     }
-}
\ No newline at end of file
+}